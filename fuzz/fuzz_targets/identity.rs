@@ -0,0 +1,13 @@
+#![no_main]
+
+use age_plugin_hpke::{identity_from_string, Identity};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Identity::from_bytes(data);
+    if let Ok(identity) = std::str::from_utf8(data) {
+        if let Ok(bytes) = identity_from_string(identity) {
+            let _ = Identity::from_bytes(&bytes);
+        }
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use age_plugin_hpke::{recipient_from_string, Recipient};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Recipient::from_bytes(data);
+    if let Ok(recipient) = std::str::from_utf8(data) {
+        if let Ok(bytes) = recipient_from_string(recipient) {
+            let _ = Recipient::from_bytes(&bytes);
+        }
+    }
+});
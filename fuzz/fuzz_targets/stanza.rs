@@ -0,0 +1,25 @@
+#![no_main]
+
+use age::Identity as _;
+use age_core::format::Stanza;
+use age_plugin_hpke::{
+    agile::{agile_derive_keypair, supported_suites},
+    Identity,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (u8, String, Vec<String>, Vec<u8>)| {
+    let (suite, tag, args, body) = input;
+    // A fixed identity of one of the enabled suites, so that both stanza modes are reachable
+    let suites = supported_suites();
+    let Some((aead, kdf, kem)) = suites.get(usize::from(suite) % suites.len().max(1)).cloned()
+    else {
+        return;
+    };
+    let Ok(keypair) = agile_derive_keypair(kem.clone(), &vec![0u8; kem.ephemeral_ikm_len()]) else {
+        return;
+    };
+    let identity = Identity::new(kem, aead, kdf, keypair.private_key(), b"");
+
+    let _ = identity.unwrap_stanza(&Stanza { tag, args, body });
+});
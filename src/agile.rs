@@ -6,19 +6,22 @@
 //! I've first attempted to write my code. Realistically, copy pasting a working code is still much simpler, therefore the copy.
 //! Can I do better? yes. Is this for now? Likely not.
 
-use bincode::{Decode, Encode};
-use hpke_pq::{
-    aead::{Aead, AeadCtxR, AeadCtxS, AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305},
-    kdf::{HkdfSha256, HkdfSha384, HkdfSha512, Kdf as KdfTrait},
-    kem::{
-        DhP256HkdfSha256, DhP384HkdfSha384, Kem as KemTrait, X25519HkdfSha256,
-        X25519Kyber768Draft00,
-    },
-    setup_receiver, setup_sender, Deserializable, HpkeError, OpModeR, OpModeS, PskBundle,
-    Serializable,
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, OnceLock, PoisonError, RwLock},
 };
 
+use bincode::{Decode, Encode};
+use hpke_pq::{aead::Aead, kdf::Kdf as KdfTrait, kem::Kem as KemTrait, HpkeError};
+
 use rand::{CryptoRng, RngCore};
+use subtle::{Choice, ConstantTimeEq};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::backend::register_builtins;
+
+pub use crate::backend::HpkeSuite;
 
 pub trait AgileAeadCtxS {
     fn seal_in_place_detached(
@@ -27,6 +30,7 @@ pub trait AgileAeadCtxS {
         aad: &[u8],
     ) -> Result<AgileAeadTag, AgileHpkeError>;
     fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError>;
+    fn export(&self, exporter_ctx: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError>;
 }
 
 pub trait AgileAeadCtxR {
@@ -37,6 +41,7 @@ pub trait AgileAeadCtxR {
         tag_bytes: &[u8],
     ) -> Result<(), AgileHpkeError>;
     fn open(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError>;
+    fn export(&self, exporter_ctx: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError>;
 }
 
 pub type AgileAeadTag = Vec<u8>;
@@ -51,45 +56,60 @@ pub enum AgileHpkeError {
     UnknownAlgIdent(&'static str, u16),
     /// Error when deserializing Public key
     InvalidKey,
+    /// When registering a suite with the id of a registered one.
+    SuiteAlreadyRegistered(SuiteId),
+    /// When registering a KEM with the id of a registered one.
+    KemAlreadyRegistered(u16),
+    /// When the suite is not one [`supported_suites`] returns.
+    UnsupportedSuite {
+        aead: AeadAlg,
+        kdf: KdfAlg,
+        kem: KemAlg,
+    },
     /// Represents an error in the `hpke` crate
     HpkeError(HpkeError),
 }
 
-// This just wraps the HpkeError
-impl From<HpkeError> for AgileHpkeError {
-    fn from(e: HpkeError) -> AgileHpkeError {
-        AgileHpkeError::HpkeError(e)
+impl fmt::Display for AgileHpkeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlgMismatch((alg1, location1), (alg2, location2)) => write!(
+                f,
+                "algorithm mismatch: {alg1} in {location1}, {alg2} in {location2}"
+            ),
+            Self::UnknownAlgIdent(alg, id) => write!(f, "unknown {alg} identifier {id:#06x}"),
+            Self::InvalidKey => write!(f, "invalid key"),
+            Self::SuiteAlreadyRegistered(id) => write!(
+                f,
+                "suite {:#06x}, {:#06x}, {:#06x} is already registered",
+                id.kem, id.kdf, id.aead
+            ),
+            Self::KemAlreadyRegistered(id) => write!(f, "KEM {id:#06x} is already registered"),
+            Self::UnsupportedSuite { aead, kdf, kem } => write!(
+                f,
+                "unsupported suite {}, {}, {}",
+                kem.name(),
+                kdf.name(),
+                aead.name()
+            ),
+            Self::HpkeError(e) => write!(f, "{e}"),
+        }
     }
 }
 
-impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AgileAeadCtxS for AeadCtxS<A, Kdf, Kem> {
-    fn seal_in_place_detached(
-        &mut self,
-        plaintext: &mut [u8],
-        aad: &[u8],
-    ) -> Result<Vec<u8>, AgileHpkeError> {
-        self.seal_in_place_detached(plaintext, aad)
-            .map(|tag| tag.to_bytes().to_vec())
-            .map_err(Into::into)
-    }
-    fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
-        self.seal(plaintext, aad).map_err(Into::into)
+impl std::error::Error for AgileHpkeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::HpkeError(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
-impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AgileAeadCtxR for AeadCtxR<A, Kdf, Kem> {
-    fn open_in_place_detached(
-        &mut self,
-        ciphertext: &mut [u8],
-        aad: &[u8],
-        tag_bytes: &[u8],
-    ) -> Result<(), AgileHpkeError> {
-        let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
-        self.open_in_place_detached(ciphertext, aad, &tag)
-            .map_err(Into::into)
-    }
-    fn open(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
-        self.open(ciphertext, aad).map_err(Into::into)
+// This just wraps the HpkeError
+impl From<HpkeError> for AgileHpkeError {
+    fn from(e: HpkeError) -> AgileHpkeError {
+        AgileHpkeError::HpkeError(e)
     }
 }
 
@@ -98,6 +118,53 @@ pub enum AeadAlg {
     AesGcm128,
     AesGcm256,
     ChaCha20Poly1305,
+    ExportOnlyAead,
+    /// An AEAD without a variant, used by a registered suite.
+    Other(u16),
+}
+
+impl AeadAlg {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AeadAlg::AesGcm128 => "AesGcm128",
+            AeadAlg::AesGcm256 => "AesGcm256",
+            AeadAlg::ChaCha20Poly1305 => "ChaCha20Poly1305",
+            AeadAlg::ExportOnlyAead => "ExportOnlyAead",
+            AeadAlg::Other(_) => "Other",
+        }
+    }
+
+    /// The AEAD of the id, [`AeadAlg::Other`] if it has no variant and a registered suite uses
+    /// it.
+    pub fn try_from_u16(id: u16) -> Result<AeadAlg, AgileHpkeError> {
+        match Self::builtin(id) {
+            Some(aead_alg) => Ok(aead_alg),
+            None if is_registered(|suite| suite.aead == id) => Ok(AeadAlg::Other(id)),
+            None => Err(AgileHpkeError::UnknownAlgIdent("AeadAlg", id)),
+        }
+    }
+
+    fn builtin(id: u16) -> Option<AeadAlg> {
+        let res = match id {
+            0x01 => AeadAlg::AesGcm128,
+            0x02 => AeadAlg::AesGcm256,
+            0x03 => AeadAlg::ChaCha20Poly1305,
+            0xFFFF => AeadAlg::ExportOnlyAead,
+            _ => return None,
+        };
+
+        Some(res)
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            AeadAlg::AesGcm128 => 0x01,
+            AeadAlg::AesGcm256 => 0x02,
+            AeadAlg::ChaCha20Poly1305 => 0x03,
+            AeadAlg::ExportOnlyAead => 0xFFFF,
+            AeadAlg::Other(id) => *id,
+        }
+    }
 }
 
 #[derive(Debug, Encode, Decode, PartialEq, Clone)]
@@ -105,6 +172,48 @@ pub enum KdfAlg {
     HkdfSha256,
     HkdfSha384,
     HkdfSha512,
+    /// A KDF without a variant, used by a registered suite.
+    Other(u16),
+}
+
+impl KdfAlg {
+    pub fn name(&self) -> &'static str {
+        match self {
+            KdfAlg::HkdfSha256 => "HkdfSha256",
+            KdfAlg::HkdfSha384 => "HkdfSha384",
+            KdfAlg::HkdfSha512 => "HkdfSha512",
+            KdfAlg::Other(_) => "Other",
+        }
+    }
+
+    /// The KDF of the id, [`KdfAlg::Other`] if it has no variant and a registered suite uses it.
+    pub fn try_from_u16(id: u16) -> Result<KdfAlg, AgileHpkeError> {
+        match Self::builtin(id) {
+            Some(kdf_alg) => Ok(kdf_alg),
+            None if is_registered(|suite| suite.kdf == id) => Ok(KdfAlg::Other(id)),
+            None => Err(AgileHpkeError::UnknownAlgIdent("KdfAlg", id)),
+        }
+    }
+
+    fn builtin(id: u16) -> Option<KdfAlg> {
+        let res = match id {
+            0x01 => KdfAlg::HkdfSha256,
+            0x02 => KdfAlg::HkdfSha384,
+            0x03 => KdfAlg::HkdfSha512,
+            _ => return None,
+        };
+
+        Some(res)
+    }
+
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            KdfAlg::HkdfSha256 => 0x01,
+            KdfAlg::HkdfSha384 => 0x02,
+            KdfAlg::HkdfSha512 => 0x03,
+            KdfAlg::Other(id) => *id,
+        }
+    }
 }
 
 #[derive(Debug, Encode, Decode, PartialEq, Clone)]
@@ -115,10 +224,12 @@ pub enum KemAlg {
     DhP256HkdfSha256,
     DhP384HkdfSha384,
     DhP521HkdfSha512,
+    /// A KEM without a variant, implemented by a registered [`AgileKem`].
+    Other(u16),
 }
 
 impl KemAlg {
-    fn name(&self) -> &'static str {
+    pub fn name(&self) -> &'static str {
         match self {
             KemAlg::DhP256HkdfSha256 => "DhP256HkdfSha256",
             KemAlg::DhP384HkdfSha384 => "DhP384HkdfSha384",
@@ -126,36 +237,86 @@ impl KemAlg {
             KemAlg::X25519HkdfSha256 => "X25519HkdfSha256",
             KemAlg::X25519Kyber768Draft00 => "X25519Kyber768Draft00",
             KemAlg::X448HkdfSha512 => "X448HkdfSha512",
+            KemAlg::Other(_) => "Other",
         }
     }
 
+    /// The KEM of the id, [`KemAlg::Other`] if it has no variant and is registered, or a
+    /// registered suite uses it.
     pub fn try_from_u16(id: u16) -> Result<KemAlg, AgileHpkeError> {
+        match Self::builtin(id) {
+            Some(kem_alg) => Ok(kem_alg),
+            None if registered_kem_by_id(id).is_some()
+                || is_registered(|suite| suite.kem == id) =>
+            {
+                Ok(KemAlg::Other(id))
+            }
+            None => Err(AgileHpkeError::UnknownAlgIdent("KemAlg", id)),
+        }
+    }
+
+    fn builtin(id: u16) -> Option<KemAlg> {
         let res = match id {
             0x10 => KemAlg::DhP256HkdfSha256,
             0x11 => KemAlg::DhP384HkdfSha384,
             0x12 => KemAlg::DhP521HkdfSha512,
             0x20 => KemAlg::X25519HkdfSha256,
-            0x22 => KemAlg::X25519Kyber768Draft00,
+            0x30 => KemAlg::X25519Kyber768Draft00,
             0x21 => KemAlg::X448HkdfSha512,
-            _ => return Err(AgileHpkeError::UnknownAlgIdent("KemAlg", id)),
+            _ => return None,
         };
 
-        Ok(res)
+        Some(res)
     }
 
-    pub fn to_u16(self) -> u16 {
+    pub fn to_u16(&self) -> u16 {
         match self {
             KemAlg::DhP256HkdfSha256 => 0x10,
             KemAlg::DhP384HkdfSha384 => 0x11,
             KemAlg::DhP521HkdfSha512 => 0x12,
             KemAlg::X25519HkdfSha256 => 0x20,
-            KemAlg::X25519Kyber768Draft00 => 0x22,
+            KemAlg::X25519Kyber768Draft00 => 0x30,
             KemAlg::X448HkdfSha512 => 0x21,
+            KemAlg::Other(id) => *id,
+        }
+    }
+
+    /// Length of an encapsulated key, `Nenc` in RFC 9180. 0 for an [`KemAlg::Other`] KEM that is
+    /// not registered
+    pub fn encapped_key_len(&self) -> usize {
+        match self {
+            KemAlg::Other(id) => registered_kem_by_id(*id).map_or(0, |kem| kem.encapped_key_len()),
+            KemAlg::DhP256HkdfSha256 => 65,
+            KemAlg::DhP384HkdfSha384 => 97,
+            KemAlg::DhP521HkdfSha512 => 133,
+            KemAlg::X25519HkdfSha256 => 32,
+            KemAlg::X25519Kyber768Draft00 => 1120,
+            KemAlg::X448HkdfSha512 => 56,
         }
     }
 
+    /// Length of the randomness encapsulation draws, `ikmE` in RFC 9180, or the concatenation of
+    /// the X25519 `ikmE` and the Kyber encapsulation seed for X25519Kyber768Draft00. 0 for an
+    /// [`KemAlg::Other`] KEM that is not registered
+    pub fn ephemeral_ikm_len(&self) -> usize {
+        match self {
+            KemAlg::Other(id) => registered_kem_by_id(*id).map_or(0, |kem| kem.ephemeral_ikm_len()),
+            KemAlg::DhP256HkdfSha256 => 32,
+            KemAlg::DhP384HkdfSha384 => 48,
+            KemAlg::DhP521HkdfSha512 => 66,
+            KemAlg::X25519HkdfSha256 => 32,
+            KemAlg::X25519Kyber768Draft00 => 64,
+            KemAlg::X448HkdfSha512 => 56,
+        }
+    }
+
+    /// KDF of the KEM, the default KDF of its suites. HkdfSha256 for an [`KemAlg::Other`] KEM
+    /// that is not registered
     pub fn kdf_alg(&self) -> KdfAlg {
         match self {
+            KemAlg::Other(id) => {
+                registered_kem_by_id(*id).map_or(KdfAlg::HkdfSha256, |kem| kem.kdf_alg())
+            }
             KemAlg::X25519HkdfSha256 => KdfAlg::HkdfSha256,
             KemAlg::X25519Kyber768Draft00 => KdfAlg::HkdfSha256,
             KemAlg::X448HkdfSha512 => KdfAlg::HkdfSha512,
@@ -180,13 +341,18 @@ impl AgilePublicKey {
         }
     }
 
-    fn try_lift<Kem: KemTrait>(&self) -> Result<Kem::PublicKey, AgileHpkeError> {
-        Kem::PublicKey::from_bytes(&self.pubkey_bytes).map_err(|e| e.into())
+    pub fn kem_alg(&self) -> &KemAlg {
+        &self.kem_alg
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         self.pubkey_bytes.clone()
     }
+
+    /// Checks the key is a valid public key of its KEM.
+    pub fn validate(&self) -> Result<(), AgileHpkeError> {
+        registered_kem(&self.kem_alg)?.validate_public_key(self)
+    }
 }
 
 #[derive(Clone)]
@@ -203,8 +369,26 @@ impl AgileEncappedKey {
         }
     }
 
-    fn try_lift<Kem: KemTrait>(&self) -> Result<Kem::EncappedKey, AgileHpkeError> {
-        Kem::EncappedKey::from_bytes(&self.encapped_key_bytes).map_err(|e| e.into())
+    pub fn kem_alg(&self) -> &KemAlg {
+        &self.kem_alg
+    }
+
+    /// Length of the encapsulated keys of the KEM, [`KemAlg::encapped_key_len`].
+    pub fn expected_len(&self) -> usize {
+        self.kem_alg.encapped_key_len()
+    }
+
+    /// Checks the key is as long as the encapsulated keys of its KEM.
+    pub fn validate(&self) -> Result<(), AgileHpkeError> {
+        let expected_len = self.expected_len();
+        if self.encapped_key_bytes.len() != expected_len {
+            return Err(HpkeError::IncorrectInputLength(
+                expected_len,
+                self.encapped_key_bytes.len(),
+            )
+            .into());
+        }
+        Ok(())
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -212,48 +396,71 @@ impl AgileEncappedKey {
     }
 }
 
-macro_rules! sk_to_pk {
-    ($sk:expr, $kem_ty:ty, $kem_alg:ident) => {{
-        type Kem = $kem_ty;
-        let kem_alg = $kem_alg;
-        let sk = $sk;
+/// Private key of any KEM. The key bytes are wiped from memory when the key is dropped, are not
+/// part of its `Debug` output, and are compared in constant time.
+#[derive(Encode, Decode, Clone)]
+pub struct AgilePrivateKey {
+    kem_alg: KemAlg,
+    privkey_bytes: Vec<u8>,
+}
 
-        let sk = sk.try_lift::<Kem>().unwrap();
+impl Drop for AgilePrivateKey {
+    fn drop(&mut self) {
+        self.privkey_bytes.zeroize();
+    }
+}
 
-        let pk = Kem::sk_to_pk(&sk);
-        AgilePublicKey {
-            kem_alg: kem_alg.clone(),
-            pubkey_bytes: pk.to_bytes().to_vec(),
-        }
-    }};
+impl ConstantTimeEq for AgilePrivateKey {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        // The KEM is public, only the key bytes need a constant-time comparison
+        Choice::from((self.kem_alg == other.kem_alg) as u8)
+            & self.privkey_bytes.ct_eq(&other.privkey_bytes)
+    }
 }
 
-#[derive(Debug, Encode, Decode, PartialEq, Clone)]
-pub struct AgilePrivateKey {
-    kem_alg: KemAlg,
-    privkey_bytes: Vec<u8>,
+impl PartialEq for AgilePrivateKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.ct_eq(other).into()
+    }
 }
 
-impl AgilePrivateKey {
-    fn try_lift<Kem: KemTrait>(&self) -> Result<Kem::PrivateKey, AgileHpkeError> {
-        Kem::PrivateKey::from_bytes(&self.privkey_bytes).map_err(|e| e.into())
+impl Eq for AgilePrivateKey {}
+
+impl fmt::Debug for AgilePrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AgilePrivateKey")
+            .field("kem_alg", &self.kem_alg)
+            .field("privkey_bytes", &"<redacted>")
+            .finish()
     }
+}
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        self.privkey_bytes.clone()
-    }
-
-    pub fn to_pk(&self) -> AgilePublicKey {
-        let kem_alg = self.kem_alg.clone();
-        match self.kem_alg {
-            KemAlg::X25519HkdfSha256 => sk_to_pk!(&self, X25519HkdfSha256, kem_alg),
-            KemAlg::X25519Kyber768Draft00 => sk_to_pk!(&self, X25519Kyber768Draft00, kem_alg),
-            KemAlg::X448HkdfSha512 => unimplemented!(),
-            KemAlg::DhP256HkdfSha256 => sk_to_pk!(&self, DhP256HkdfSha256, kem_alg),
-            KemAlg::DhP384HkdfSha384 => sk_to_pk!(&self, DhP384HkdfSha384, kem_alg),
-            KemAlg::DhP521HkdfSha512 => unimplemented!(),
+impl AgilePrivateKey {
+    pub fn new(kem_alg: KemAlg, privkey_bytes: &[u8]) -> Self {
+        Self {
+            kem_alg,
+            privkey_bytes: privkey_bytes.to_vec(),
         }
     }
+
+    pub fn kem_alg(&self) -> &KemAlg {
+        &self.kem_alg
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        Zeroizing::new(self.privkey_bytes.clone())
+    }
+
+    /// Checks the key is a valid private key of its KEM.
+    pub fn validate(&self) -> Result<(), AgileHpkeError> {
+        registered_kem(&self.kem_alg)?.validate_private_key(self)
+    }
+
+    /// The public key of the private key. Errors if the key is invalid or its KEM is not
+    /// registered.
+    pub fn to_pk(&self) -> Result<AgilePublicKey, AgileHpkeError> {
+        registered_kem(&self.kem_alg)?.sk_to_pk(self)
+    }
 }
 
 #[derive(Clone)]
@@ -264,10 +471,6 @@ impl AgileKeypair {
         AgileKeypair(sk, pk)
     }
 
-    fn try_lift<Kem: KemTrait>(&self) -> Result<(Kem::PrivateKey, Kem::PublicKey), AgileHpkeError> {
-        Ok((self.0.try_lift::<Kem>()?, self.1.try_lift::<Kem>()?))
-    }
-
     fn validate(&self) -> Result<(), AgileHpkeError> {
         if self.0.kem_alg != self.1.kem_alg {
             Err(AgileHpkeError::AlgMismatch(
@@ -288,63 +491,41 @@ impl AgileKeypair {
     }
 }
 
-// The leg work of agile_gen_keypair
-macro_rules! do_gen_keypair {
-    ($kem_ty:ty, $kem_alg:ident, $csprng:ident) => {{
-        type Kem = $kem_ty;
-        let kem_alg = $kem_alg;
-        let csprng = $csprng;
-
-        let (sk, pk) = Kem::gen_keypair(csprng);
-        let sk = AgilePrivateKey {
-            kem_alg: kem_alg.clone(),
-            privkey_bytes: sk.to_bytes().to_vec(),
-        };
-        let pk = AgilePublicKey {
-            kem_alg: kem_alg.clone(),
-            pubkey_bytes: pk.to_bytes().to_vec(),
-        };
-
-        AgileKeypair(sk, pk)
-    }};
+/// Errors if the KEM is not registered. Built in KEMs are registered when their feature is
+/// enabled, others with [`register_kem`].
+pub fn agile_gen_keypair<R: CryptoRng + RngCore>(
+    kem_alg: KemAlg,
+    csprng: &mut R,
+) -> Result<AgileKeypair, AgileHpkeError> {
+    registered_kem(&kem_alg)?.gen_keypair(csprng)
 }
 
-pub fn agile_gen_keypair<R: CryptoRng + RngCore>(kem_alg: KemAlg, csprng: &mut R) -> AgileKeypair {
-    match kem_alg {
-        KemAlg::X25519HkdfSha256 => do_gen_keypair!(X25519HkdfSha256, kem_alg, csprng),
-        KemAlg::X25519Kyber768Draft00 => do_gen_keypair!(X25519Kyber768Draft00, kem_alg, csprng),
-        KemAlg::X448HkdfSha512 => unimplemented!(),
-        KemAlg::DhP256HkdfSha256 => do_gen_keypair!(DhP256HkdfSha256, kem_alg, csprng),
-        KemAlg::DhP384HkdfSha384 => do_gen_keypair!(DhP384HkdfSha384, kem_alg, csprng),
-        KemAlg::DhP521HkdfSha512 => unimplemented!(),
-    }
+/// Deterministic keypair derivation from input keying material, `DeriveKeyPair` in RFC 9180.
+/// Errors if the KEM is not registered.
+pub fn agile_derive_keypair(kem_alg: KemAlg, ikm: &[u8]) -> Result<AgileKeypair, AgileHpkeError> {
+    registered_kem(&kem_alg)?.derive_keypair(ikm)
 }
 
 #[derive(Clone)]
-pub struct AgileOpModeR<'a> {
+pub struct AgileOpModeR {
     kem_alg: KemAlg,
-    op_mode_ty: AgileOpModeRTy<'a>,
+    op_mode_ty: AgileOpModeRTy,
 }
 
-impl<'a> AgileOpModeR<'a> {
-    pub fn new(kem_alg: KemAlg, op_mode_ty: AgileOpModeRTy<'static>) -> Self {
-        AgileOpModeR::<'static> {
+impl AgileOpModeR {
+    pub fn new(kem_alg: KemAlg, op_mode_ty: AgileOpModeRTy) -> Self {
+        AgileOpModeR {
             kem_alg,
             op_mode_ty,
         }
     }
 
-    fn try_lift<Kem: KemTrait>(self) -> Result<OpModeR<'a, Kem>, AgileHpkeError> {
-        let res = match self.op_mode_ty {
-            AgileOpModeRTy::Base => OpModeR::Base,
-            AgileOpModeRTy::Psk(bundle) => OpModeR::Psk(bundle.try_lift()?),
-            AgileOpModeRTy::Auth(pk) => OpModeR::Auth(pk.try_lift::<Kem>()?),
-            AgileOpModeRTy::AuthPsk(pk, bundle) => {
-                OpModeR::AuthPsk(pk.try_lift::<Kem>()?, bundle.try_lift()?)
-            }
-        };
+    pub fn kem_alg(&self) -> &KemAlg {
+        &self.kem_alg
+    }
 
-        Ok(res)
+    pub fn op_mode_ty(&self) -> &AgileOpModeRTy {
+        &self.op_mode_ty
     }
 
     fn validate(&self) -> Result<(), AgileHpkeError> {
@@ -379,40 +560,36 @@ impl<'a> AgileOpModeR<'a> {
 }
 
 #[derive(Clone)]
-pub enum AgileOpModeRTy<'a> {
+pub enum AgileOpModeRTy {
     Base,
-    Psk(AgilePskBundle<'a>),
+    Psk(AgilePskBundle),
     Auth(AgilePublicKey),
-    AuthPsk(AgilePublicKey, AgilePskBundle<'a>),
+    AuthPsk(AgilePublicKey, AgilePskBundle),
 }
 
 #[derive(Clone)]
-pub struct AgileOpModeS<'a> {
+pub struct AgileOpModeS {
     kem_alg: KemAlg,
-    op_mode_ty: AgileOpModeSTy<'a>,
+    op_mode_ty: AgileOpModeSTy,
 }
 
-impl<'a> AgileOpModeS<'a> {
-    pub fn new(kem_alg: KemAlg, op_mode_ty: AgileOpModeSTy<'static>) -> Self {
-        AgileOpModeS::<'static> {
+impl AgileOpModeS {
+    pub fn new(kem_alg: KemAlg, op_mode_ty: AgileOpModeSTy) -> Self {
+        AgileOpModeS {
             kem_alg,
             op_mode_ty,
         }
     }
 
-    fn try_lift<Kem: KemTrait>(self) -> Result<OpModeS<'a, Kem>, AgileHpkeError> {
-        let res = match self.op_mode_ty {
-            AgileOpModeSTy::Base => OpModeS::Base,
-            AgileOpModeSTy::Psk(bundle) => OpModeS::Psk(bundle.try_lift()?),
-            AgileOpModeSTy::Auth(keypair) => OpModeS::Auth(keypair.try_lift::<Kem>()?),
-            AgileOpModeSTy::AuthPsk(keypair, bundle) => {
-                OpModeS::AuthPsk(keypair.try_lift::<Kem>()?, bundle.try_lift()?)
-            }
-        };
+    pub fn kem_alg(&self) -> &KemAlg {
+        &self.kem_alg
+    }
 
-        Ok(res)
+    pub fn op_mode_ty(&self) -> &AgileOpModeSTy {
+        &self.op_mode_ty
     }
 
+    // The lifted mode borrows the preshared key, which stays owned by the agile mode
     fn validate(&self) -> Result<(), AgileHpkeError> {
         match &self.op_mode_ty {
             AgileOpModeSTy::Auth(keypair) => {
@@ -447,92 +624,209 @@ impl<'a> AgileOpModeS<'a> {
 }
 
 #[derive(Clone)]
-pub enum AgileOpModeSTy<'a> {
+pub enum AgileOpModeSTy {
     Base,
-    Psk(AgilePskBundle<'a>),
+    Psk(AgilePskBundle),
     Auth(AgileKeypair),
-    AuthPsk(AgileKeypair, AgilePskBundle<'a>),
+    AuthPsk(AgileKeypair, AgilePskBundle),
+}
+
+/// Preshared key and its id, copied into the bundle and zeroized when it is dropped.
+#[derive(Clone)]
+pub struct AgilePskBundle {
+    psk: Zeroizing<Vec<u8>>,
+    psk_id: Zeroizing<Vec<u8>>,
 }
 
-#[derive(Clone, Copy)]
-pub struct AgilePskBundle<'a>(PskBundle<'a>);
+impl AgilePskBundle {
+    pub fn new(psk: &[u8], psk_id: &[u8]) -> Self {
+        AgilePskBundle {
+            psk: Zeroizing::new(psk.to_vec()),
+            psk_id: Zeroizing::new(psk_id.to_vec()),
+        }
+    }
+
+    pub fn psk(&self) -> &[u8] {
+        &self.psk
+    }
 
-impl<'a> AgilePskBundle<'a> {
-    fn try_lift(self) -> Result<PskBundle<'a>, AgileHpkeError> {
-        Ok(self.0)
+    pub fn psk_id(&self) -> &[u8] {
+        &self.psk_id
     }
 }
 
-// This macro takes in all the supported AEADs, KDFs, and KEMs, and dispatches the given test
-// vector to the test case with the appropriate types
-macro_rules! hpke_dispatch {
-    // Step 1: Roll up the AEAD, KDF, and KEM types into tuples. We'll unroll them later
-    ($to_set:ident, $to_match:ident,
-     ($( $aead_ty:ident ),*), ($( $kdf_ty:ident ),*), ($( $kem_ty:ident ),*), $rng_ty:ident,
-     $callback:ident, $( $callback_args:ident ),* ) => {
-        hpke_dispatch!(@tup1
-            $to_set, $to_match,
-            ($( $aead_ty ),*), ($( $kdf_ty ),*), ($( $kem_ty ),*), $rng_ty,
-            $callback, ($( $callback_args ),*)
-        )
-    };
+/// RFC 9180 identifiers of the AEAD, KDF, and KEM of a suite, the key of the suite registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SuiteId {
+    pub kem: u16,
+    pub kdf: u16,
+    pub aead: u16,
+}
 
-    // Step 2: Expand with respect to every AEAD
-    (@tup1
-     $to_set:ident, $to_match:ident,
-     ($( $aead_ty:ident ),*), $kdf_tup:tt, $kem_tup:tt, $rng_ty:tt,
-     $callback:ident, $callback_args:tt) => {
-        $(
-            hpke_dispatch!(@tup2
-                $to_set, $to_match,
-                $aead_ty, $kdf_tup, $kem_tup, $rng_ty,
-                $callback, $callback_args
-            );
-        )*
-    };
+impl SuiteId {
+    pub fn new(aead_alg: &AeadAlg, kdf_alg: &KdfAlg, kem_alg: &KemAlg) -> Self {
+        SuiteId {
+            kem: kem_alg.to_u16(),
+            kdf: kdf_alg.to_u16(),
+            aead: aead_alg.to_u16(),
+        }
+    }
+}
 
-    // Step 3: Expand with respect to every KDF
-    (@tup2
-     $to_set:ident, $to_match:ident,
-     $aead_ty:ident, ($( $kdf_ty:ident ),*), $kem_tup:tt, $rng_ty:tt,
-     $callback:ident, $callback_args:tt) => {
-        $(
-            hpke_dispatch!(@tup3
-                $to_set, $to_match,
-                $aead_ty, $kdf_ty, $kem_tup, $rng_ty,
-                $callback, $callback_args
-            );
-        )*
-    };
+/// Random number generator of [`AgileSuite::setup_sender`]. Implemented by every `CryptoRng`.
+pub trait AgileRng: CryptoRng + RngCore {}
+
+impl<R: CryptoRng + RngCore + ?Sized> AgileRng for R {}
+
+/// Implementation of an HPKE suite, which [`agile_setup_sender`] and [`agile_setup_receiver`]
+/// dispatch to.
+///
+/// The agile functions check that the algorithms of the mode and keys match before calling the
+/// suite. Lifting the keys to the suite's types, and validating them, is up to the suite.
+pub trait AgileSuite: Send + Sync {
+    fn id(&self) -> SuiteId;
+
+    fn setup_sender(
+        &self,
+        mode: &AgileOpModeS,
+        pk_recip: &AgilePublicKey,
+        info: &[u8],
+        csprng: &mut dyn AgileRng,
+    ) -> Result<(AgileEncappedKey, Box<dyn AgileAeadCtxS>), AgileHpkeError>;
+
+    fn setup_receiver(
+        &self,
+        mode: &AgileOpModeR,
+        recip_keypair: &AgileKeypair,
+        encapped_key: &AgileEncappedKey,
+        info: &[u8],
+    ) -> Result<Box<dyn AgileAeadCtxR>, AgileHpkeError>;
+}
 
-    // Step 4: Expand with respect to every KEM
-    (@tup3
-     $to_set:ident, $to_match:ident,
-     $aead_ty:ident, $kdf_ty:ident, ($( $kem_ty:ident ),*), $rng_ty:tt,
-     $callback:ident, $callback_args:tt) => {
-        $(
-            hpke_dispatch!(@base
-                $to_set, $to_match,
-                $aead_ty, $kdf_ty, $kem_ty, $rng_ty,
-                $callback, $callback_args
-            );
-        )*
-    };
+/// Implementation of a KEM, doing the key operations of [`agile_gen_keypair`],
+/// [`agile_derive_keypair`], and of the agile keys of the KEM.
+///
+/// Suites using the KEM are registered separately, with [`register_suite`].
+pub trait AgileKem: Send + Sync {
+    fn id(&self) -> u16;
 
-    // Step 5: Now that we're only dealing with 1 type of each kind, do the dispatch. If the test
-    // vector matches the IDs of these types, run the test case.
-    (@base
-     $to_set:ident, $to_match:ident,
-     $aead_ty:ident, $kdf_ty:ident, $kem_ty:ident, $rng_ty:ident,
-     $callback:ident, ($( $callback_args:ident ),*)) => {
-        if let (AeadAlg::$aead_ty, KemAlg::$kem_ty, KdfAlg::$kdf_ty) = $to_match
-        {
-            $to_set = Some($callback::<$aead_ty, $kdf_ty, $kem_ty, $rng_ty>($( $callback_args ),*));
-        }
-    };
+    /// Length of an encapsulated key, see [`KemAlg::encapped_key_len`].
+    fn encapped_key_len(&self) -> usize;
+
+    /// Length of the randomness encapsulation draws, see [`KemAlg::ephemeral_ikm_len`].
+    fn ephemeral_ikm_len(&self) -> usize;
+
+    fn kdf_alg(&self) -> KdfAlg;
+
+    fn gen_keypair(&self, csprng: &mut dyn AgileRng) -> Result<AgileKeypair, AgileHpkeError>;
+
+    fn derive_keypair(&self, ikm: &[u8]) -> Result<AgileKeypair, AgileHpkeError>;
+
+    fn validate_public_key(&self, pk: &AgilePublicKey) -> Result<(), AgileHpkeError>;
+
+    fn validate_private_key(&self, sk: &AgilePrivateKey) -> Result<(), AgileHpkeError>;
+
+    fn sk_to_pk(&self, sk: &AgilePrivateKey) -> Result<AgilePublicKey, AgileHpkeError>;
 }
 
-// The leg work of agile_setup_receiver
+/// Registered suites and KEMs.
+#[derive(Default)]
+pub(crate) struct Registry {
+    pub(crate) suites: HashMap<SuiteId, Arc<dyn AgileSuite>>,
+    pub(crate) kems: HashMap<u16, Arc<dyn AgileKem>>,
+}
+
+fn registry() -> &'static RwLock<Registry> {
+    static REGISTRY: OnceLock<RwLock<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut registry = Registry::default();
+        register_builtins(&mut registry);
+        RwLock::new(registry)
+    })
+}
+
+/// Makes a suite available to [`agile_setup_sender`] and [`agile_setup_receiver`], and to the
+/// age recipients and identities using it. Errors if a suite with the same id is registered,
+/// built in suites included, which [`replace_suite`] replaces.
+pub fn register_suite(suite: Arc<dyn AgileSuite>) -> Result<(), AgileHpkeError> {
+    let mut registry = registry().write().unwrap_or_else(PoisonError::into_inner);
+    let id = suite.id();
+    if registry.suites.contains_key(&id) {
+        return Err(AgileHpkeError::SuiteAlreadyRegistered(id));
+    }
+    registry.suites.insert(id, suite);
+    Ok(())
+}
+
+/// [`register_suite`], replacing the suite with the same id. Returns the replaced suite.
+pub fn replace_suite(suite: Arc<dyn AgileSuite>) -> Option<Arc<dyn AgileSuite>> {
+    let mut registry = registry().write().unwrap_or_else(PoisonError::into_inner);
+    registry.suites.insert(suite.id(), suite)
+}
+
+/// Makes the key operations of a KEM available to the agile keys of the KEM. Errors if a KEM
+/// with the same id is registered.
+pub fn register_kem(kem: Arc<dyn AgileKem>) -> Result<(), AgileHpkeError> {
+    let mut registry = registry().write().unwrap_or_else(PoisonError::into_inner);
+    let id = kem.id();
+    if registry.kems.contains_key(&id) {
+        return Err(AgileHpkeError::KemAlreadyRegistered(id));
+    }
+    registry.kems.insert(id, kem);
+    Ok(())
+}
+
+fn registered_kem_by_id(id: u16) -> Option<Arc<dyn AgileKem>> {
+    let registry = registry().read().unwrap_or_else(PoisonError::into_inner);
+    registry.kems.get(&id).cloned()
+}
+
+fn registered_kem(kem_alg: &KemAlg) -> Result<Arc<dyn AgileKem>, AgileHpkeError> {
+    registered_kem_by_id(kem_alg.to_u16())
+        .ok_or(AgileHpkeError::UnknownAlgIdent("KemAlg", kem_alg.to_u16()))
+}
+
+// Whether a registered suite has the id
+fn is_registered(f: impl Fn(&SuiteId) -> bool) -> bool {
+    let registry = registry().read().unwrap_or_else(PoisonError::into_inner);
+    registry.suites.keys().any(f)
+}
+
+fn registered_suite(
+    aead_alg: &AeadAlg,
+    kdf_alg: &KdfAlg,
+    kem_alg: &KemAlg,
+) -> Option<Arc<dyn AgileSuite>> {
+    let registry = registry().read().unwrap_or_else(PoisonError::into_inner);
+    registry
+        .suites
+        .get(&SuiteId::new(aead_alg, kdf_alg, kem_alg))
+        .cloned()
+}
+
+/// Whether [`agile_setup_sender`] and [`agile_setup_receiver`] support this suite.
+pub fn is_supported_suite(aead_alg: &AeadAlg, kdf_alg: &KdfAlg, kem_alg: &KemAlg) -> bool {
+    registered_suite(aead_alg, kdf_alg, kem_alg).is_some()
+}
+
+/// Every suite [`agile_setup_sender`] and [`agile_setup_receiver`] support, ordered by the ids of
+/// the KEM, then the KDF, then the AEAD. Algorithms without a variant are `Other`.
+pub fn supported_suites() -> Vec<(AeadAlg, KdfAlg, KemAlg)> {
+    let registry = registry().read().unwrap_or_else(PoisonError::into_inner);
+    let mut ids: Vec<&SuiteId> = registry.suites.keys().collect();
+    ids.sort();
+    ids.into_iter()
+        .map(|id| {
+            (
+                AeadAlg::builtin(id.aead).unwrap_or(AeadAlg::Other(id.aead)),
+                KdfAlg::builtin(id.kdf).unwrap_or(KdfAlg::Other(id.kdf)),
+                KemAlg::builtin(id.kem).unwrap_or(KemAlg::Other(id.kem)),
+            )
+        })
+        .collect()
+}
+
+// The leg work of agile_setup_sender
 pub fn do_setup_sender<A, Kdf, Kem, R>(
     mode: &AgileOpModeS,
     pk_recip: &AgilePublicKey,
@@ -545,17 +839,7 @@ where
     Kem: 'static + KemTrait,
     R: CryptoRng + RngCore,
 {
-    let kem_alg = mode.kem_alg.clone();
-    let mode = mode.clone().try_lift::<Kem>()?;
-    let pk_recip = pk_recip.try_lift::<Kem>()?;
-
-    let (encapped_key, aead_ctx) = setup_sender::<A, Kdf, Kem, _>(&mode, &pk_recip, info, csprng)?;
-    let encapped_key = AgileEncappedKey {
-        kem_alg,
-        encapped_key_bytes: encapped_key.to_bytes().to_vec(),
-    };
-
-    Ok((encapped_key, Box::new(aead_ctx)))
+    HpkeSuite::<A, Kdf, Kem>::new().setup_sender(mode, pk_recip, info, csprng)
 }
 
 pub fn agile_setup_sender<R: CryptoRng + RngCore>(
@@ -582,37 +866,92 @@ pub fn agile_setup_sender<R: CryptoRng + RngCore>(
         ));
     }
 
-    // The triple we dispatch on
-    let to_match = (aead_alg, kem_alg.clone(), kdf_alg);
+    let suite = registered_suite(&aead_alg, &kdf_alg, &kem_alg).ok_or(
+        AgileHpkeError::UnsupportedSuite {
+            aead: aead_alg,
+            kdf: kdf_alg,
+            kem: kem_alg,
+        },
+    )?;
+    suite.setup_sender(mode, pk_recip, info, csprng)
+}
+
+// Replays the given bytes as randomness, so that encapsulation is deterministic. A KEM reading
+// more bytes than there are gets zeros, and the overrun is reported after encapsulation, as
+// `fill_bytes` cannot fail.
+struct IkmRng<'a> {
+    ikm: &'a [u8],
+    // Bytes the KEM asked for, past the end of ikm included
+    requested: usize,
+}
 
-    // This gets overwritten by the below macro call. It's None iff dispatch failed.
-    type AgileHpkeRes = Result<(AgileEncappedKey, Box<dyn AgileAeadCtxS>), AgileHpkeError>;
-    let mut res: Option<AgileHpkeRes> = None;
+impl RngCore for IkmRng<'_> {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
 
-    #[rustfmt::skip]
-    hpke_dispatch!(
-        res, to_match,
-        (ChaCha20Poly1305, AesGcm128, AesGcm256),
-        (HkdfSha256, HkdfSha384, HkdfSha512),
-        (X25519HkdfSha256, X25519Kyber768Draft00, DhP256HkdfSha256),
-        R,
-        do_setup_sender,
-            mode,
-            pk_recip,
-            info,
-            csprng
-    );
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
 
-    if res.is_none() {
-        panic!("DHKEM({}) isn't impelmented yet!", kem_alg.name());
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        if self.try_fill_bytes(dest).is_err() {
+            dest.fill(0);
+        }
     }
 
-    res.unwrap()
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.requested += dest.len();
+        if dest.len() > self.ikm.len() {
+            self.ikm = &[];
+            return Err(rand::Error::new("ephemeral ikm is exhausted"));
+        }
+        let (head, tail) = self.ikm.split_at(dest.len());
+        dest.copy_from_slice(head);
+        self.ikm = tail;
+        Ok(())
+    }
 }
 
-// The leg work of agile_setup_receiver. The Dummy type parameter is so that it can be used with
-// the hpke_dispatch! macro. The macro expects its callback function to have 4 type parameters
-pub fn do_setup_receiver<A, Kdf, Kem, Dummy>(
+impl CryptoRng for IkmRng<'_> {}
+
+/// [`agile_setup_sender`] with the ephemeral key derived from `ikm_e` instead of a random number
+/// generator.
+///
+/// `ikm_e` is [`KemAlg::ephemeral_ikm_len`] bytes long. Reusing it breaks the security of HPKE,
+/// this is meant for test vectors. Returns [`HpkeError::IncorrectInputLength`] if the KEM reads
+/// more randomness than that, which registered KEMs can do by mistake.
+#[allow(clippy::too_many_arguments)]
+pub fn agile_setup_sender_with_ephemeral_ikm(
+    aead_alg: AeadAlg,
+    kdf_alg: KdfAlg,
+    kem_alg: KemAlg,
+    mode: &AgileOpModeS,
+    pk_recip: &AgilePublicKey,
+    info: &[u8],
+    ikm_e: &[u8],
+) -> Result<(AgileEncappedKey, Box<dyn AgileAeadCtxS>), AgileHpkeError> {
+    let ikm_len = kem_alg.ephemeral_ikm_len();
+    if ikm_e.len() != ikm_len {
+        return Err(HpkeError::IncorrectInputLength(ikm_len, ikm_e.len()).into());
+    }
+    let mut rng = IkmRng {
+        ikm: ikm_e,
+        requested: 0,
+    };
+    let res = agile_setup_sender(aead_alg, kdf_alg, kem_alg, mode, pk_recip, info, &mut rng);
+    if rng.requested > ikm_len {
+        return Err(HpkeError::IncorrectInputLength(rng.requested, ikm_len).into());
+    }
+    res
+}
+
+// The leg work of agile_setup_receiver
+pub fn do_setup_receiver<A, Kdf, Kem>(
     mode: &AgileOpModeR,
     recip_keypair: &AgileKeypair,
     encapped_key: &AgileEncappedKey,
@@ -623,12 +962,7 @@ where
     Kdf: 'static + KdfTrait,
     Kem: 'static + KemTrait,
 {
-    let mode = mode.clone().try_lift::<Kem>()?;
-    let (sk_recip, _) = recip_keypair.try_lift::<Kem>()?;
-    let encapped_key = encapped_key.try_lift::<Kem>()?;
-
-    let aead_ctx = setup_receiver::<A, Kdf, Kem>(&mode, &sk_recip, &encapped_key, info)?;
-    Ok(Box::new(aead_ctx))
+    HpkeSuite::<A, Kdf, Kem>::new().setup_receiver(mode, recip_keypair, encapped_key, info)
 }
 
 pub fn agile_setup_receiver(
@@ -661,34 +995,168 @@ pub fn agile_setup_receiver(
             (encapped_key.kem_alg.name(), "encapped_key::kem_alg"),
         ));
     }
+    encapped_key.validate()?;
+
+    let suite = registered_suite(&aead_alg, &kdf_alg, &kem_alg).ok_or(
+        AgileHpkeError::UnsupportedSuite {
+            aead: aead_alg,
+            kdf: kdf_alg,
+            kem: kem_alg,
+        },
+    )?;
+    suite.setup_receiver(mode, recip_keypair, encapped_key, info)
+}
 
-    // The triple we dispatch on
-    let to_match = (aead_alg, kem_alg.clone(), kdf_alg);
+/// Single-shot encryption, `Seal` in RFC 9180: sets up a sender context and seals one message.
+#[allow(clippy::too_many_arguments)]
+pub fn agile_seal<R: CryptoRng + RngCore>(
+    aead_alg: AeadAlg,
+    kdf_alg: KdfAlg,
+    kem_alg: KemAlg,
+    mode: &AgileOpModeS,
+    pk_recip: &AgilePublicKey,
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    csprng: &mut R,
+) -> Result<(AgileEncappedKey, Vec<u8>), AgileHpkeError> {
+    let (encapped_key, mut ctx) =
+        agile_setup_sender(aead_alg, kdf_alg, kem_alg, mode, pk_recip, info, csprng)?;
+    let ciphertext = ctx.seal(plaintext, aad)?;
+    Ok((encapped_key, ciphertext))
+}
 
-    // This gets overwritten by the below macro call. It's None iff dispatch failed.
-    let mut res: Option<Result<Box<dyn AgileAeadCtxR>, AgileHpkeError>> = None;
+/// Single-shot decryption, `Open` in RFC 9180: sets up a receiver context and opens one message.
+#[allow(clippy::too_many_arguments)]
+pub fn agile_open(
+    aead_alg: AeadAlg,
+    kdf_alg: KdfAlg,
+    kem_alg: KemAlg,
+    mode: &AgileOpModeR,
+    recip_keypair: &AgileKeypair,
+    encapped_key: &AgileEncappedKey,
+    info: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, AgileHpkeError> {
+    let mut ctx = agile_setup_receiver(
+        aead_alg,
+        kdf_alg,
+        kem_alg,
+        mode,
+        recip_keypair,
+        encapped_key,
+        info,
+    )?;
+    ctx.open(ciphertext, aad)
+}
+
+/// Sender context sealing a sequence of messages, each one with the next nonce.
+///
+/// Sealing past the message limit fails with [`HpkeError::MessageLimitReached`]. The limit is the
+/// one of RFC 9180 unless lowered with [`AgileSequenceS::with_message_limit`].
+pub struct AgileSequenceS {
+    ctx: Box<dyn AgileAeadCtxS>,
+    seq: u64,
+    limit: u64,
+}
+
+impl AgileSequenceS {
+    pub fn new(ctx: Box<dyn AgileAeadCtxS>) -> Self {
+        AgileSequenceS {
+            ctx,
+            seq: 0,
+            limit: u64::MAX,
+        }
+    }
+
+    /// Allows at most `limit` messages to be sealed.
+    pub fn with_message_limit(mut self, limit: u64) -> Self {
+        self.limit = limit;
+        self
+    }
 
-    // Dummy type to give to the macro. do_setup_receiver doesn't use an RNG, so it doesn't need a
-    // concrete RNG type. We give it the unit type to make it happy.
-    type Unit = ();
+    /// Sequence number of the next message, the number of messages sealed so far.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        if self.seq >= self.limit {
+            return Err(HpkeError::MessageLimitReached.into());
+        }
+        let ciphertext = self.ctx.seal(plaintext, aad)?;
+        self.seq += 1;
+        Ok(ciphertext)
+    }
 
-    #[rustfmt::skip]
-    hpke_dispatch!(
-        res, to_match,
-        (ChaCha20Poly1305, AesGcm128, AesGcm256),
-        (HkdfSha256, HkdfSha384, HkdfSha512),
-        (X25519HkdfSha256, X25519Kyber768Draft00, DhP256HkdfSha256),
-        Unit,
-        do_setup_receiver,
-            mode,
-            recip_keypair,
-            encapped_key,
-            info
-    );
+    /// Seals the `(plaintext, aad)` messages in order, stopping at the first error.
+    pub fn seal_all(
+        &mut self,
+        messages: &[(&[u8], &[u8])],
+    ) -> Result<Vec<Vec<u8>>, AgileHpkeError> {
+        messages
+            .iter()
+            .map(|(plaintext, aad)| self.seal(plaintext, aad))
+            .collect()
+    }
 
-    if res.is_none() {
-        panic!("DHKEM({}) isn't impelmented yet!", kem_alg.name());
+    pub fn export(&self, exporter_ctx: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError> {
+        self.ctx.export(exporter_ctx, len)
     }
+}
 
-    res.unwrap()
+/// Receiver context opening a sequence of messages, in the order they were sealed.
+///
+/// A message that fails to open does not advance the sequence. Opening past the message limit
+/// fails with [`HpkeError::MessageLimitReached`].
+pub struct AgileSequenceR {
+    ctx: Box<dyn AgileAeadCtxR>,
+    seq: u64,
+    limit: u64,
+}
+
+impl AgileSequenceR {
+    pub fn new(ctx: Box<dyn AgileAeadCtxR>) -> Self {
+        AgileSequenceR {
+            ctx,
+            seq: 0,
+            limit: u64::MAX,
+        }
+    }
+
+    /// Allows at most `limit` messages to be opened.
+    pub fn with_message_limit(mut self, limit: u64) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Sequence number of the next message, the number of messages opened so far.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    pub fn open(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        if self.seq >= self.limit {
+            return Err(HpkeError::MessageLimitReached.into());
+        }
+        let plaintext = self.ctx.open(ciphertext, aad)?;
+        self.seq += 1;
+        Ok(plaintext)
+    }
+
+    /// Opens the `(ciphertext, aad)` messages in order, stopping at the first error.
+    pub fn open_all(
+        &mut self,
+        messages: &[(&[u8], &[u8])],
+    ) -> Result<Vec<Vec<u8>>, AgileHpkeError> {
+        messages
+            .iter()
+            .map(|(ciphertext, aad)| self.open(ciphertext, aad))
+            .collect()
+    }
+
+    pub fn export(&self, exporter_ctx: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError> {
+        self.ctx.export(exporter_ctx, len)
+    }
 }
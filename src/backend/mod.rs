@@ -0,0 +1,436 @@
+//! The HPKE implementations behind the agile key operations and the built in suites. hpke_pq
+//! implements every KEM, with the `backend-hpke` feature the upstream hpke crate takes over the
+//! KEMs it implements.
+
+use crate::agile::{AeadAlg, AgileHpkeError, AgilePrivateKey, AgilePublicKey, KemAlg, Registry};
+
+/// The error of a key operation on a KEM hpke_pq does not implement with the enabled features.
+fn disabled_kem(kem_alg: &KemAlg) -> AgileHpkeError {
+    AgileHpkeError::UnknownAlgIdent("KemAlg", kem_alg.to_u16())
+}
+
+// Defines the KEM `$agile_kem`, the suite `$suite`, and `register` adding them for the listed
+// KEMs, of an hpke crate. hpke_pq is a fork of hpke, so both crates have the same API, except for
+// their error type which `$lift_error` converts. The KEMs are listed with the feature enabling
+// them, and have the same name in the crate as in `KemAlg`. The agile types are expected in scope.
+//
+// hpke does not expose the public key of a private key, so public keys are derived by hpke_pq
+// whatever the backend, see `sk_to_pk`. Both derive the same keys.
+macro_rules! hpke_backend {
+    (
+        $hpke:ident,
+        $lift_error:path,
+        $agile_kem:ident,
+        $suite:ident,
+        { $($feature:literal => $kem:ident),* $(,)? }
+    ) => {
+        use std::{marker::PhantomData, sync::Arc};
+
+        use $hpke::{
+            aead::{Aead, AeadCtxR, AeadCtxS, AeadTag},
+            kdf::{HkdfSha256, HkdfSha384, HkdfSha512, Kdf as KdfTrait},
+            kem::Kem as KemTrait,
+            setup_receiver, setup_sender, Deserializable, HpkeError, OpModeR, OpModeS, PskBundle,
+            Serializable,
+        };
+        use zeroize::Zeroizing;
+
+        /// Adds the KEMs and their suites, replacing the registered ones.
+        pub(crate) fn register(registry: &mut Registry) {
+            $(
+                #[cfg(feature = $feature)]
+                register_kem::<$hpke::kem::$kem>(registry, KemAlg::$kem);
+            )*
+        }
+
+        // The function pointers make the KEM and the suite Send and Sync, whatever the algorithm
+        // types are
+        struct $agile_kem<Kem> {
+            kem_alg: KemAlg,
+            kem: PhantomData<fn() -> Kem>,
+        }
+
+        impl<Kem: KemTrait> AgileKem for $agile_kem<Kem> {
+            fn id(&self) -> u16 {
+                Kem::KEM_ID
+            }
+
+            fn encapped_key_len(&self) -> usize {
+                self.kem_alg.encapped_key_len()
+            }
+
+            fn ephemeral_ikm_len(&self) -> usize {
+                self.kem_alg.ephemeral_ikm_len()
+            }
+
+            fn kdf_alg(&self) -> KdfAlg {
+                self.kem_alg.kdf_alg()
+            }
+
+            fn gen_keypair(
+                &self,
+                mut csprng: &mut dyn AgileRng,
+            ) -> Result<AgileKeypair, AgileHpkeError> {
+                let (sk, pk) = Kem::gen_keypair(&mut csprng);
+                Ok(to_agile_keypair::<Kem>(&self.kem_alg, sk, pk))
+            }
+
+            fn derive_keypair(&self, ikm: &[u8]) -> Result<AgileKeypair, AgileHpkeError> {
+                let (sk, pk) = Kem::derive_keypair(ikm);
+                Ok(to_agile_keypair::<Kem>(&self.kem_alg, sk, pk))
+            }
+
+            fn validate_public_key(&self, pk: &AgilePublicKey) -> Result<(), AgileHpkeError> {
+                lift_public_key::<Kem>(pk).map(|_| ())
+            }
+
+            fn validate_private_key(&self, sk: &AgilePrivateKey) -> Result<(), AgileHpkeError> {
+                lift_private_key::<Kem>(sk).map(|_| ())
+            }
+
+            fn sk_to_pk(&self, sk: &AgilePrivateKey) -> Result<AgilePublicKey, AgileHpkeError> {
+                super::sk_to_pk(sk)
+            }
+        }
+
+        fn to_agile_keypair<Kem: KemTrait>(
+            kem_alg: &KemAlg,
+            sk: Kem::PrivateKey,
+            pk: Kem::PublicKey,
+        ) -> AgileKeypair {
+            AgileKeypair::new(
+                AgilePrivateKey::new(kem_alg.clone(), &Zeroizing::new(sk.to_bytes())),
+                AgilePublicKey::new(kem_alg.clone(), &pk.to_bytes()),
+            )
+        }
+
+        fn lift_public_key<Kem: KemTrait>(
+            pk: &AgilePublicKey,
+        ) -> Result<Kem::PublicKey, AgileHpkeError> {
+            Kem::PublicKey::from_bytes(&pk.to_bytes()).map_err($lift_error)
+        }
+
+        fn lift_private_key<Kem: KemTrait>(
+            sk: &AgilePrivateKey,
+        ) -> Result<Kem::PrivateKey, AgileHpkeError> {
+            Kem::PrivateKey::from_bytes(&sk.to_bytes()).map_err($lift_error)
+        }
+
+        fn lift_keypair<Kem: KemTrait>(
+            keypair: &AgileKeypair,
+        ) -> Result<(Kem::PrivateKey, Kem::PublicKey), AgileHpkeError> {
+            Ok((
+                lift_private_key::<Kem>(keypair.private_key())?,
+                lift_public_key::<Kem>(keypair.public_key())?,
+            ))
+        }
+
+        // The lifted mode borrows the preshared key, which stays owned by the agile mode
+        fn lift_psk_bundle(bundle: &AgilePskBundle) -> PskBundle<'_> {
+            PskBundle {
+                psk: bundle.psk(),
+                psk_id: bundle.psk_id(),
+            }
+        }
+
+        fn lift_mode_s<Kem: KemTrait>(
+            mode: &AgileOpModeS,
+        ) -> Result<OpModeS<'_, Kem>, AgileHpkeError> {
+            Ok(match mode.op_mode_ty() {
+                AgileOpModeSTy::Base => OpModeS::Base,
+                AgileOpModeSTy::Psk(bundle) => OpModeS::Psk(lift_psk_bundle(bundle)),
+                AgileOpModeSTy::Auth(keypair) => OpModeS::Auth(lift_keypair::<Kem>(keypair)?),
+                AgileOpModeSTy::AuthPsk(keypair, bundle) => {
+                    OpModeS::AuthPsk(lift_keypair::<Kem>(keypair)?, lift_psk_bundle(bundle))
+                }
+            })
+        }
+
+        fn lift_mode_r<Kem: KemTrait>(
+            mode: &AgileOpModeR,
+        ) -> Result<OpModeR<'_, Kem>, AgileHpkeError> {
+            Ok(match mode.op_mode_ty() {
+                AgileOpModeRTy::Base => OpModeR::Base,
+                AgileOpModeRTy::Psk(bundle) => OpModeR::Psk(lift_psk_bundle(bundle)),
+                AgileOpModeRTy::Auth(pk) => OpModeR::Auth(lift_public_key::<Kem>(pk)?),
+                AgileOpModeRTy::AuthPsk(pk, bundle) => {
+                    OpModeR::AuthPsk(lift_public_key::<Kem>(pk)?, lift_psk_bundle(bundle))
+                }
+            })
+        }
+
+        #[doc = concat!(
+            "Suite implemented with the ",
+            stringify!($hpke),
+            " types `A`, `Kdf`, and `Kem`."
+        )]
+        #[allow(clippy::type_complexity)]
+        pub struct $suite<A, Kdf, Kem>(PhantomData<fn() -> (A, Kdf, Kem)>);
+
+        impl<A, Kdf, Kem> $suite<A, Kdf, Kem> {
+            pub fn new() -> Self {
+                $suite(PhantomData)
+            }
+        }
+
+        impl<A, Kdf, Kem> Default for $suite<A, Kdf, Kem> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl<A, Kdf, Kem> AgileSuite for $suite<A, Kdf, Kem>
+        where
+            A: 'static + Aead,
+            Kdf: 'static + KdfTrait,
+            Kem: 'static + KemTrait,
+        {
+            fn id(&self) -> SuiteId {
+                SuiteId {
+                    kem: Kem::KEM_ID,
+                    kdf: Kdf::KDF_ID,
+                    aead: A::AEAD_ID,
+                }
+            }
+
+            fn setup_sender(
+                &self,
+                mode: &AgileOpModeS,
+                pk_recip: &AgilePublicKey,
+                info: &[u8],
+                mut csprng: &mut dyn AgileRng,
+            ) -> Result<(AgileEncappedKey, Box<dyn AgileAeadCtxS>), AgileHpkeError> {
+                let kem_mode = lift_mode_s::<Kem>(mode)?;
+                let kem_pk = lift_public_key::<Kem>(pk_recip)?;
+
+                let (encapped_key, aead_ctx) =
+                    setup_sender::<A, Kdf, Kem, _>(&kem_mode, &kem_pk, info, &mut csprng)
+                        .map_err($lift_error)?;
+                let encapped_key =
+                    AgileEncappedKey::new(mode.kem_alg().clone(), &encapped_key.to_bytes());
+
+                Ok((encapped_key, Box::new(aead_ctx)))
+            }
+
+            fn setup_receiver(
+                &self,
+                mode: &AgileOpModeR,
+                recip_keypair: &AgileKeypair,
+                encapped_key: &AgileEncappedKey,
+                info: &[u8],
+            ) -> Result<Box<dyn AgileAeadCtxR>, AgileHpkeError> {
+                let kem_mode = lift_mode_r::<Kem>(mode)?;
+                let (sk_recip, _) = lift_keypair::<Kem>(recip_keypair)?;
+                let encapped_key =
+                    Kem::EncappedKey::from_bytes(&encapped_key.to_bytes()).map_err($lift_error)?;
+
+                let aead_ctx =
+                    setup_receiver::<A, Kdf, Kem>(&kem_mode, &sk_recip, &encapped_key, info)
+                        .map_err($lift_error)?;
+                Ok(Box::new(aead_ctx))
+            }
+        }
+
+        // hpke panics when sealing or opening with an export-only context, agile contexts error
+        // instead
+        impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AgileAeadCtxS for AeadCtxS<A, Kdf, Kem> {
+            fn seal_in_place_detached(
+                &mut self,
+                plaintext: &mut [u8],
+                aad: &[u8],
+            ) -> Result<AgileAeadTag, AgileHpkeError> {
+                if super::is_export_only(A::AEAD_ID) {
+                    return Err($lift_error(HpkeError::SealError));
+                }
+                self.seal_in_place_detached(plaintext, aad)
+                    .map(|tag| tag.to_bytes().to_vec())
+                    .map_err($lift_error)
+            }
+            fn seal(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+                if super::is_export_only(A::AEAD_ID) {
+                    return Err($lift_error(HpkeError::SealError));
+                }
+                self.seal(plaintext, aad).map_err($lift_error)
+            }
+            fn export(&self, exporter_ctx: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError> {
+                let mut secret = vec![0u8; len];
+                self.export(exporter_ctx, &mut secret).map_err($lift_error)?;
+                Ok(secret)
+            }
+        }
+
+        impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AgileAeadCtxR for AeadCtxR<A, Kdf, Kem> {
+            fn open_in_place_detached(
+                &mut self,
+                ciphertext: &mut [u8],
+                aad: &[u8],
+                tag_bytes: &[u8],
+            ) -> Result<(), AgileHpkeError> {
+                if super::is_export_only(A::AEAD_ID) {
+                    return Err($lift_error(HpkeError::OpenError));
+                }
+                let tag = AeadTag::<A>::from_bytes(tag_bytes).map_err($lift_error)?;
+                self.open_in_place_detached(ciphertext, aad, &tag)
+                    .map_err($lift_error)
+            }
+            fn open(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+                if super::is_export_only(A::AEAD_ID) {
+                    return Err($lift_error(HpkeError::OpenError));
+                }
+                self.open(ciphertext, aad).map_err($lift_error)
+            }
+            fn export(&self, exporter_ctx: &[u8], len: usize) -> Result<Vec<u8>, AgileHpkeError> {
+                let mut secret = vec![0u8; len];
+                self.export(exporter_ctx, &mut secret).map_err($lift_error)?;
+                Ok(secret)
+            }
+        }
+
+        // The KEM, with every KDF and enabled AEAD of the crate
+        fn register_kem<Kem: 'static + KemTrait>(registry: &mut Registry, kem_alg: KemAlg) {
+            fn register<A: 'static + Aead, Kdf: 'static + KdfTrait, Kem: 'static + KemTrait>(
+                registry: &mut Registry,
+            ) {
+                let suite = $suite::<A, Kdf, Kem>::new();
+                registry.suites.insert(suite.id(), Arc::new(suite));
+            }
+
+            fn register_kdf<Kdf: 'static + KdfTrait, Kem: 'static + KemTrait>(
+                registry: &mut Registry,
+            ) {
+                #[cfg(feature = "chacha20poly1305")]
+                register::<$hpke::aead::ChaCha20Poly1305, Kdf, Kem>(registry);
+                #[cfg(feature = "aes-gcm")]
+                register::<$hpke::aead::AesGcm128, Kdf, Kem>(registry);
+                #[cfg(feature = "aes-gcm")]
+                register::<$hpke::aead::AesGcm256, Kdf, Kem>(registry);
+                #[cfg(feature = "export-only")]
+                register::<$hpke::aead::ExportOnlyAead, Kdf, Kem>(registry);
+            }
+
+            let kem = $agile_kem::<Kem> {
+                kem_alg,
+                kem: PhantomData,
+            };
+            registry.kems.insert(kem.id(), Arc::new(kem));
+            register_kdf::<HkdfSha256, Kem>(registry);
+            register_kdf::<HkdfSha384, Kem>(registry);
+            register_kdf::<HkdfSha512, Kem>(registry);
+        }
+    };
+}
+
+mod pq;
+// hpke only implements the X25519 and P-256 KEMs
+#[cfg(all(
+    feature = "backend-hpke",
+    any(feature = "x25519", feature = "nist-p256")
+))]
+mod upstream;
+
+pub use pq::HpkeSuite;
+
+/// Whether the AEAD id is the export-only AEAD, which only exports secrets and neither seals nor
+/// opens.
+pub(crate) fn is_export_only(aead_id: u16) -> bool {
+    aead_id == AeadAlg::ExportOnlyAead.to_u16()
+}
+
+/// The public key of the private key, derived by hpke_pq whatever the backend of the KEM.
+fn sk_to_pk(sk: &AgilePrivateKey) -> Result<AgilePublicKey, AgileHpkeError> {
+    pq::sk_to_pk(sk)
+}
+
+// The KEMs and suites a backend takes over are replaced
+pub(crate) fn register_builtins(registry: &mut Registry) {
+    pq::register(registry);
+    #[cfg(all(
+        feature = "backend-hpke",
+        any(feature = "x25519", feature = "nist-p256")
+    ))]
+    upstream::register(registry);
+}
+
+#[cfg(all(
+    test,
+    feature = "backend-hpke",
+    feature = "x25519",
+    feature = "chacha20poly1305"
+))]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::agile::{
+        AeadAlg, AgileOpModeR, AgileOpModeRTy, AgileOpModeS, AgileOpModeSTy, AgilePskBundle,
+        KdfAlg, SuiteId,
+    };
+
+    fn registries() -> (Registry, Registry) {
+        let mut pq_registry = Registry::default();
+        pq::register(&mut pq_registry);
+        let mut upstream_registry = Registry::default();
+        upstream::register(&mut upstream_registry);
+        (pq_registry, upstream_registry)
+    }
+
+    #[test]
+    fn derived_keys_match() {
+        let kem_alg = KemAlg::X25519HkdfSha256;
+        let ikm = [5u8; 32];
+        let (pq_registry, upstream_registry) = registries();
+        let pq_keypair = pq_registry.kems[&kem_alg.to_u16()]
+            .derive_keypair(&ikm)
+            .unwrap();
+        let upstream_keypair = upstream_registry.kems[&kem_alg.to_u16()]
+            .derive_keypair(&ikm)
+            .unwrap();
+        assert_eq!(
+            pq_keypair.private_key().to_bytes(),
+            upstream_keypair.private_key().to_bytes()
+        );
+        assert_eq!(
+            pq_keypair.public_key().to_bytes(),
+            upstream_keypair.public_key().to_bytes()
+        );
+    }
+
+    #[test]
+    fn backends_interoperate() {
+        let kem_alg = KemAlg::X25519HkdfSha256;
+        let id = SuiteId::new(&AeadAlg::ChaCha20Poly1305, &KdfAlg::HkdfSha256, &kem_alg);
+        let (pq_registry, upstream_registry) = registries();
+        let pq_suite = &pq_registry.suites[&id];
+        let upstream_suite = &upstream_registry.suites[&id];
+
+        let mut csprng = StdRng::seed_from_u64(0);
+        let kem = &pq_registry.kems[&kem_alg.to_u16()];
+        let recip_keypair = kem.gen_keypair(&mut csprng).unwrap();
+        let sender_keypair = kem.gen_keypair(&mut csprng).unwrap();
+        let psk = AgilePskBundle::new(b"preshared key", b"psk id");
+
+        for (sender, receiver) in [(pq_suite, upstream_suite), (upstream_suite, pq_suite)] {
+            let mode_s = AgileOpModeS::new(
+                kem_alg.clone(),
+                AgileOpModeSTy::AuthPsk(sender_keypair.clone(), psk.clone()),
+            );
+            let mode_r = AgileOpModeR::new(
+                kem_alg.clone(),
+                AgileOpModeRTy::AuthPsk(sender_keypair.public_key().clone(), psk.clone()),
+            );
+            let (encapped_key, mut ctx_s) = sender
+                .setup_sender(&mode_s, recip_keypair.public_key(), b"info", &mut csprng)
+                .unwrap();
+            let mut ctx_r = receiver
+                .setup_receiver(&mode_r, &recip_keypair, &encapped_key, b"info")
+                .unwrap();
+
+            let ciphertext = ctx_s.seal(b"plaintext", b"aad").unwrap();
+            assert_eq!(ctx_r.open(&ciphertext, b"aad").unwrap(), b"plaintext");
+            assert_eq!(
+                ctx_s.export(b"exporter", 32).unwrap(),
+                ctx_r.export(b"exporter", 32).unwrap()
+            );
+        }
+    }
+}
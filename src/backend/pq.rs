@@ -0,0 +1,38 @@
+//! hpke_pq, the backend of every KEM.
+
+use super::disabled_kem;
+use crate::agile::{
+    AgileAeadCtxR, AgileAeadCtxS, AgileAeadTag, AgileEncappedKey, AgileHpkeError, AgileKem,
+    AgileKeypair, AgileOpModeR, AgileOpModeRTy, AgileOpModeS, AgileOpModeSTy, AgilePrivateKey,
+    AgilePskBundle, AgilePublicKey, AgileRng, AgileSuite, KdfAlg, KemAlg, Registry, SuiteId,
+};
+
+hpke_backend!(hpke_pq, AgileHpkeError::from, HpkeKem, HpkeSuite, {
+    "x25519" => X25519HkdfSha256,
+    "pq-hybrid" => X25519Kyber768Draft00,
+    "nist-p256" => DhP256HkdfSha256,
+    "nist-p384" => DhP384HkdfSha384,
+});
+
+pub(super) fn sk_to_pk(sk: &AgilePrivateKey) -> Result<AgilePublicKey, AgileHpkeError> {
+    fn to_pk<Kem: KemTrait>(sk: &AgilePrivateKey) -> Result<AgilePublicKey, AgileHpkeError> {
+        let kem_sk = lift_private_key::<Kem>(sk)?;
+        Ok(AgilePublicKey::new(
+            sk.kem_alg().clone(),
+            &Kem::sk_to_pk(&kem_sk).to_bytes(),
+        ))
+    }
+
+    match sk.kem_alg() {
+        #[cfg(feature = "x25519")]
+        KemAlg::X25519HkdfSha256 => to_pk::<hpke_pq::kem::X25519HkdfSha256>(sk),
+        #[cfg(feature = "pq-hybrid")]
+        KemAlg::X25519Kyber768Draft00 => to_pk::<hpke_pq::kem::X25519Kyber768Draft00>(sk),
+        #[cfg(feature = "nist-p256")]
+        KemAlg::DhP256HkdfSha256 => to_pk::<hpke_pq::kem::DhP256HkdfSha256>(sk),
+        #[cfg(feature = "nist-p384")]
+        KemAlg::DhP384HkdfSha384 => to_pk::<hpke_pq::kem::DhP384HkdfSha384>(sk),
+        #[allow(unreachable_patterns)]
+        _ => Err(disabled_kem(sk.kem_alg())),
+    }
+}
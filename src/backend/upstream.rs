@@ -0,0 +1,31 @@
+//! The upstream hpke crate, the backend of the X25519 and NIST P-256 KEMs with the
+//! `backend-hpke` feature. hpke_pq is a fork of it, so both produce the same wire format.
+
+use crate::agile::{
+    AgileAeadCtxR, AgileAeadCtxS, AgileAeadTag, AgileEncappedKey, AgileHpkeError, AgileKem,
+    AgileKeypair, AgileOpModeR, AgileOpModeRTy, AgileOpModeS, AgileOpModeSTy, AgilePrivateKey,
+    AgilePskBundle, AgilePublicKey, AgileRng, AgileSuite, KdfAlg, KemAlg, Registry, SuiteId,
+};
+
+// The agile errors carry the hpke_pq error, which has every variant of the hpke one
+fn lift_error(e: HpkeError) -> AgileHpkeError {
+    use hpke_pq::HpkeError as PqError;
+
+    AgileHpkeError::HpkeError(match e {
+        HpkeError::MessageLimitReached => PqError::MessageLimitReached,
+        HpkeError::OpenError => PqError::OpenError,
+        HpkeError::SealError => PqError::SealError,
+        HpkeError::KdfOutputTooLong => PqError::KdfOutputTooLong,
+        HpkeError::ValidationError => PqError::ValidationError,
+        HpkeError::EncapError => PqError::EncapError,
+        HpkeError::DecapError => PqError::DecapError,
+        HpkeError::IncorrectInputLength(expected, given) => {
+            PqError::IncorrectInputLength(expected, given)
+        }
+    })
+}
+
+hpke_backend!(hpke, lift_error, UpstreamKem, UpstreamSuite, {
+    "x25519" => X25519HkdfSha256,
+    "nist-p256" => DhP256HkdfSha256,
+});
@@ -1,74 +1,202 @@
-use age_plugin_hpke::agile::{AeadAlg, KemAlg};
-use clap::{Args, Parser, ValueEnum};
+use std::path::PathBuf;
+
+use age_plugin_hpke::agile::{AeadAlg, KdfAlg, KemAlg};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 /// Plugin for age to interact with Hybrid Public Key Encryption (HPKE)
 ///
 /// Example:
-///     $ age-plugin-hpke --generate > my_id.key
-///     $ cat my_id.key | grep 'recipient' | sed 's/.*\(age1.*\)/\1/' > my_id.key.pub
+///     $ age-plugin-hpke generate -o my_id.key --recipient-output my_id.key.pub
 ///     $ tar cvz ~/data | age -R my_id.key.pub > data.tar.gz.age
 ///     $ age --decrypt -i my_id.key -o data.tar.gz data.tar.gz.age
 #[derive(Parser)]
 #[command(author, version, about, verbatim_doc_comment)]
-#[command(propagate_version = true)]
+#[command(propagate_version = true, args_conflicts_with_subcommands = true)]
+#[command(arg_required_else_help = true)]
 pub struct Cli {
     #[clap(flatten)]
     pub verbose: clap_verbosity_flag::Verbosity,
-    #[arg(long, hide = true, group = "action")]
+    /// Run the age plugin state machine, used by age clients
+    #[arg(long, hide = true)]
     pub age_plugin: Option<String>,
-    #[command(flatten)]
-    pub generate: Option<GenerateArg>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Generate a new identity, and its recipient
+    Generate(GenerateArgs),
+    /// Print the recipient of an identity file
+    Recipient {
+        /// Identity file, "-" for standard input
+        #[arg(default_value = "-")]
+        identity: String,
+    },
+    /// Print the compact form of a recipient, resolved using the key directory
+    Compact {
+        /// Full recipient, "age1hpke1..."
+        recipient: String,
+    },
+    /// Describe recipients, identities, or the hpke stanzas of an age file
+    Inspect {
+        /// File to inspect, "-" for standard input
+        #[arg(default_value = "-")]
+        file: String,
+        /// Output inspection results as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the supported combinations of KEM, KDF, and AEAD
+    Suites,
+    /// Encrypt data to a recipient with single-shot HPKE, outside of age
+    Seal {
+        /// Recipient to encrypt to, "age1hpke1..."
+        #[arg(short, long)]
+        recipient: String,
+        #[clap(flatten)]
+        args: HpkeArgs,
+    },
+    /// Decrypt data encrypted with the seal command
+    Open {
+        /// Identity file, "-" for standard input
+        #[arg(short, long)]
+        identity: String,
+        #[clap(flatten)]
+        args: HpkeArgs,
+    },
+}
+
+#[derive(Args)]
+pub struct HpkeArgs {
+    /// HPKE info, binding the encryption to an application context
+    #[arg(long, default_value = "")]
+    pub info: String,
+    /// Additional authenticated data
+    #[arg(long, default_value = "")]
+    pub aad: String,
+    /// Encode sealed data as base64, the encapsulated key followed by the ciphertext
+    #[arg(long)]
+    pub base64: bool,
+    /// Write the result to a file instead of standard output
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// Input file, "-" for standard input
+    #[arg(default_value = "-")]
+    pub input: String,
 }
 
 #[derive(Args)]
-pub struct GenerateArg {
-    #[arg(long, default_value_t = false, group = "action")]
-    pub generate: bool,
-    #[arg(long, requires = "action")]
-    pub associated_data: Option<String>,
-    #[arg(long, requires = "action")]
-    pub kem: Option<Kem>,
-    #[arg(long, requires = "action")]
-    pub aead: Option<Aead>,
+pub struct GenerateArgs {
+    /// Associated data bound to every file encrypted to the recipient
+    #[arg(long, default_value = "")]
+    pub associated_data: String,
+    /// Key encapsulation mechanism
+    #[arg(long, value_enum, default_value_t = Kem::default())]
+    pub kem: Kem,
+    /// Key derivation function, defaults to the one the KEM uses
+    #[arg(long, value_enum)]
+    pub kdf: Option<Kdf>,
+    /// Authenticated encryption algorithm. With export-only, the file key is wrapped with a
+    /// secret exported from the HPKE context
+    #[arg(long, value_enum, default_value_t = Aead::default())]
+    pub aead: Aead,
+    /// Protect the identity with a passphrase, prompted for when it is used
+    #[arg(long)]
+    pub passphrase: bool,
+    /// Write the identity to a file instead of standard output
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+    /// Write the recipient to a file
+    #[arg(long)]
+    pub recipient_output: Option<PathBuf>,
 }
 
 #[derive(Clone, ValueEnum)]
 pub enum Kem {
+    #[cfg(feature = "x25519")]
     X25519HkdfSha256,
+    #[cfg(feature = "pq-hybrid")]
     X25519Kyber768Draft00,
+    #[cfg(feature = "nist-p256")]
     P256HkdfSha256,
-    P521HkdfSha512,
+    #[cfg(feature = "nist-p384")]
+    P384HkdfSha384,
 }
 
 impl Kem {
     pub fn to_alg(&self) -> KemAlg {
         match self {
+            #[cfg(feature = "x25519")]
             Self::X25519HkdfSha256 => KemAlg::X25519HkdfSha256,
+            #[cfg(feature = "pq-hybrid")]
             Self::X25519Kyber768Draft00 => KemAlg::X25519Kyber768Draft00,
+            #[cfg(feature = "nist-p256")]
             Self::P256HkdfSha256 => KemAlg::DhP256HkdfSha256,
-            Self::P521HkdfSha512 => KemAlg::DhP521HkdfSha512,
+            #[cfg(feature = "nist-p384")]
+            Self::P384HkdfSha384 => KemAlg::DhP384HkdfSha384,
+        }
+    }
+}
+
+impl Default for Kem {
+    /// X25519HkdfSha256, or the first KEM enabled
+    fn default() -> Self {
+        Self::value_variants()[0].clone()
+    }
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum Kdf {
+    HkdfSha256,
+    HkdfSha384,
+    HkdfSha512,
+}
+
+impl Kdf {
+    pub fn to_alg(&self) -> KdfAlg {
+        match self {
+            Self::HkdfSha256 => KdfAlg::HkdfSha256,
+            Self::HkdfSha384 => KdfAlg::HkdfSha384,
+            Self::HkdfSha512 => KdfAlg::HkdfSha512,
         }
     }
 }
 
 #[derive(Clone, ValueEnum)]
 pub enum Aead {
+    #[cfg(feature = "chacha20poly1305")]
+    ChaCha20Poly1305,
+    #[cfg(feature = "aes-gcm")]
     AesGcm128,
+    #[cfg(feature = "aes-gcm")]
     AesGcm256,
-    ChaCha20Poly1305,
+    #[cfg(feature = "export-only")]
+    ExportOnly,
 }
 
 impl Aead {
     pub fn to_alg(&self) -> AeadAlg {
         match self {
+            #[cfg(feature = "chacha20poly1305")]
+            Self::ChaCha20Poly1305 => AeadAlg::ChaCha20Poly1305,
+            #[cfg(feature = "aes-gcm")]
             Self::AesGcm128 => AeadAlg::AesGcm128,
+            #[cfg(feature = "aes-gcm")]
             Self::AesGcm256 => AeadAlg::AesGcm256,
-            Self::ChaCha20Poly1305 => AeadAlg::ChaCha20Poly1305,
+            #[cfg(feature = "export-only")]
+            Self::ExportOnly => AeadAlg::ExportOnlyAead,
         }
     }
 }
 
-#[allow(dead_code)]
+impl Default for Aead {
+    /// ChaCha20Poly1305, or the first AEAD enabled
+    fn default() -> Self {
+        Self::value_variants()[0].clone()
+    }
+}
+
 pub fn build() -> Cli {
     Cli::parse()
 }
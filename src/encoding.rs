@@ -0,0 +1,154 @@
+//! Bech32 encoding of plugin recipients and identities.
+//!
+//! BIP-173 restricts bech32 strings to 90 characters, which HPKE keys exceed as soon as associated
+//! data is used, and post-quantum keys exceed by several kilobytes. age encodes plugin recipients
+//! and identities with the bech32 checksum regardless of their length, and this module does the
+//! same. Instead of the 90 characters limit, strings are bounded by [`MAX_ENCODED_LEN`].
+//!
+//! The bech32 checksum guarantees to detect up to 4 errors for strings shorter than 90 characters.
+//! Beyond that, it still detects random errors with probability 1 - 2^-30. Only the original
+//! bech32 variant is accepted, bech32m strings are rejected.
+
+use std::fmt;
+
+use bech32::{FromBase32, ToBase32, Variant};
+
+/// Maximum length of an encoded recipient or identity, human readable part included.
+///
+/// An X25519Kyber768Draft00 identity is about 4000 characters long. The limit leaves room for
+/// passphrase protection and associated data.
+pub const MAX_ENCODED_LEN: usize = 8192;
+
+#[derive(Debug, PartialEq)]
+pub enum EncodingError {
+    /// The string, or the string that would be produced, is longer than [`MAX_ENCODED_LEN`].
+    TooLong(usize),
+    /// The string uses the bech32m checksum.
+    InvalidVariant,
+    /// Represents an error in the `bech32` crate
+    Bech32(bech32::Error),
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLong(len) => write!(
+                f,
+                "encoded string is {len} characters long, maximum is {MAX_ENCODED_LEN}"
+            ),
+            Self::InvalidVariant => write!(f, "expected bech32 checksum, found bech32m"),
+            Self::Bech32(e) => write!(f, "invalid bech32 string: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for EncodingError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Bech32(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<bech32::Error> for EncodingError {
+    fn from(e: bech32::Error) -> Self {
+        Self::Bech32(e)
+    }
+}
+
+/// Length of the string [`encode`] produces for `data` with `hrp`.
+pub fn encoded_len(hrp: &str, data_len: usize) -> usize {
+    // separator, 5 bits per character, and a 6 characters checksum
+    hrp.len() + 1 + (data_len * 8 + 4) / 5 + 6
+}
+
+/// Encode `data` as a lowercase bech32 string.
+pub fn encode(hrp: &str, data: &[u8]) -> Result<String, EncodingError> {
+    let len = encoded_len(hrp, data.len());
+    if len > MAX_ENCODED_LEN {
+        return Err(EncodingError::TooLong(len));
+    }
+    bech32::encode(hrp, data.to_base32(), Variant::Bech32).map_err(Into::into)
+}
+
+/// Decode a bech32 string, returning its lowercase human readable part and data.
+pub fn decode(s: &str) -> Result<(String, Vec<u8>), EncodingError> {
+    if s.len() > MAX_ENCODED_LEN {
+        return Err(EncodingError::TooLong(s.len()));
+    }
+    let (hrp, data, variant) = bech32::decode(s)?;
+    if variant != Variant::Bech32 {
+        return Err(EncodingError::InvalidVariant);
+    }
+    Ok((hrp, Vec::from_base32(&data)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HRP: &str = "age1hpke";
+
+    // X25519Kyber768Draft00 public and private key sizes
+    const KYBER_PUBLIC_KEY_LEN: usize = 32 + 1184;
+    const KYBER_PRIVATE_KEY_LEN: usize = 32 + 2400;
+
+    #[test]
+    fn roundtrip_at_kyber_sizes() {
+        for len in [0, 32, KYBER_PUBLIC_KEY_LEN, KYBER_PRIVATE_KEY_LEN] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encoded = encode(HRP, &data).unwrap();
+            assert_eq!(encoded.len(), encoded_len(HRP, len));
+            assert_eq!(decode(&encoded).unwrap(), (HRP.to_owned(), data));
+        }
+    }
+
+    #[cfg(all(feature = "pq-hybrid", feature = "aes-gcm"))]
+    #[test]
+    fn accepts_existing_kyber_keys() {
+        use crate::agile::{AeadAlg, KdfAlg, KemAlg};
+
+        let (identity, recipient) = crate::new_identity(
+            KemAlg::X25519Kyber768Draft00,
+            AeadAlg::AesGcm256,
+            KdfAlg::HkdfSha384,
+            "",
+        )
+        .unwrap();
+        // Keys as produced before the length policy was introduced
+        for (hrp, data) in [("age-plugin-hpke-", identity.to_vec()), (HRP, recipient)] {
+            let encoded = bech32::encode(hrp, data.to_base32(), Variant::Bech32).unwrap();
+            assert!(encoded.len() > 90);
+            assert_eq!(decode(&encoded).unwrap(), (hrp.to_owned(), data.clone()));
+            assert_eq!(
+                decode(&encoded.to_uppercase()).unwrap(),
+                (hrp.to_owned(), data)
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_long_strings() {
+        let data = vec![0u8; MAX_ENCODED_LEN];
+        assert!(matches!(encode(HRP, &data), Err(EncodingError::TooLong(_))));
+
+        let encoded = bech32::encode(HRP, data.to_base32(), Variant::Bech32).unwrap();
+        assert_eq!(decode(&encoded), Err(EncodingError::TooLong(encoded.len())));
+    }
+
+    #[test]
+    fn rejects_invalid_strings() {
+        let data = vec![42u8; KYBER_PUBLIC_KEY_LEN];
+        let encoded = bech32::encode(HRP, data.to_base32(), Variant::Bech32m).unwrap();
+        assert_eq!(decode(&encoded), Err(EncodingError::InvalidVariant));
+
+        let mut encoded = encode(HRP, &data).unwrap();
+        let last = encoded.pop().unwrap();
+        encoded.push(if last == 'q' { 'p' } else { 'q' });
+        assert_eq!(
+            decode(&encoded),
+            Err(EncodingError::Bech32(bech32::Error::InvalidChecksum))
+        );
+    }
+}
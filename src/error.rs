@@ -0,0 +1,175 @@
+//! Error type of the library functions, and of `Identity` and `Recipient`.
+//!
+//! The `agile` and `typed` modules are the HPKE layer under them, and return `AgileHpkeError`,
+//! which converts into [`Error::Hpke`].
+
+use std::{fmt, io, path::PathBuf};
+
+use bincode::error::DecodeError;
+
+use crate::{
+    agile::{AeadAlg, AgileHpkeError, KdfAlg, KemAlg},
+    encoding::EncodingError,
+    keydir::KEY_DIR_ENV,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// A recipient or identity string is not valid bech32, or is too long.
+    Encoding(EncodingError),
+    /// Recipient or identity bytes do not decode to a valid key.
+    InvalidKey(DecodeError),
+    /// The combination of KEM, KDF, and AEAD is not supported.
+    UnsupportedSuite {
+        kem: KemAlg,
+        kdf: KdfAlg,
+        aead: AeadAlg,
+    },
+    /// An HPKE operation failed.
+    Hpke(AgileHpkeError),
+    /// A passphrase protected identity cannot be decrypted.
+    Decrypt(age::DecryptError),
+    /// No key directory is configured to resolve compact recipients.
+    NoKeyDirectory,
+    /// A compact recipient is not in the key directory at the given path.
+    UnknownCompactRecipient(PathBuf),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Encoding(e) => write!(f, "invalid key encoding: {e}"),
+            Self::InvalidKey(e) => write!(f, "invalid key: {e}"),
+            Self::UnsupportedSuite { kem, kdf, aead } => write!(
+                f,
+                "unsupported suite {}, {}, {}",
+                kem.name(),
+                kdf.name(),
+                aead.name()
+            ),
+            Self::Hpke(e) => write!(f, "HPKE error: {e}"),
+            Self::Decrypt(e) => write!(f, "cannot decrypt identity: {e}"),
+            Self::NoKeyDirectory => write!(
+                f,
+                "cannot locate key directory to resolve compact recipient, set {KEY_DIR_ENV}"
+            ),
+            Self::UnknownCompactRecipient(path) => write!(
+                f,
+                "compact recipient not found in key directory {}",
+                path.display()
+            ),
+            Self::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Encoding(e) => Some(e),
+            Self::InvalidKey(e) => Some(e),
+            Self::Hpke(e) => Some(e),
+            Self::Decrypt(e) => Some(e),
+            Self::Io(e) => Some(e),
+            Self::UnsupportedSuite { .. }
+            | Self::NoKeyDirectory
+            | Self::UnknownCompactRecipient(_) => None,
+        }
+    }
+}
+
+impl From<EncodingError> for Error {
+    fn from(e: EncodingError) -> Self {
+        Self::Encoding(e)
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(e: DecodeError) -> Self {
+        Self::InvalidKey(e)
+    }
+}
+
+impl From<AgileHpkeError> for Error {
+    fn from(e: AgileHpkeError) -> Self {
+        Self::Hpke(e)
+    }
+}
+
+impl From<age::DecryptError> for Error {
+    fn from(e: age::DecryptError) -> Self {
+        Self::Decrypt(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+    use crate::{agile::supported_suites, identity_from_string, new_identity, open, seal};
+
+    #[test]
+    fn unsupported_suite() {
+        let e = new_identity(
+            KemAlg::X448HkdfSha512,
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha512,
+            "",
+        )
+        .unwrap_err();
+        assert!(matches!(e, Error::UnsupportedSuite { .. }));
+        assert_eq!(
+            e.to_string(),
+            "unsupported suite X448HkdfSha512, HkdfSha512, ChaCha20Poly1305"
+        );
+    }
+
+    #[test]
+    fn associated_data_too_long_to_encode() {
+        let (aead, kdf, kem) = supported_suites().remove(0);
+        let associated_data = "a".repeat(crate::encoding::MAX_ENCODED_LEN);
+        let e = new_identity(kem, aead, kdf, &associated_data).unwrap_err();
+        assert!(matches!(
+            e,
+            Error::Encoding(crate::encoding::EncodingError::TooLong(_))
+        ));
+    }
+
+    #[test]
+    fn errors_have_sources() {
+        let e = identity_from_string("not bech32").unwrap_err();
+        assert!(matches!(e, Error::Encoding(_)));
+        assert!(e.source().is_some());
+
+        let e = seal(b"not a recipient", b"", b"", b"").unwrap_err();
+        assert!(matches!(e, Error::InvalidKey(_)));
+        assert!(e.source().is_some());
+
+        // Export-only suites do not seal
+        let sealing = supported_suites()
+            .into_iter()
+            .find(|(aead, _, _)| *aead != AeadAlg::ExportOnlyAead);
+        if let Some((aead, kdf, kem)) = sealing {
+            let (identity, recipient) = new_identity(kem, aead, kdf, "").unwrap();
+            let sealed = seal(&recipient, b"info", b"", b"secret").unwrap();
+            let e = open(&identity, b"other", b"", &sealed).unwrap_err();
+            assert!(matches!(e, Error::Hpke(_)));
+            assert!(e.source().and_then(|e| e.source()).is_some());
+        }
+
+        // Errors convert to boxed errors with `?`
+        let boxed = || -> Result<(), Box<dyn std::error::Error>> {
+            identity_from_string("")?;
+            Ok(())
+        };
+        assert!(boxed().is_err());
+    }
+}
@@ -0,0 +1,502 @@
+//! Decode recipients, identities, and hpke stanzas of age files.
+//!
+//! [`inspect`] accepts an identity file, recipients one per line, or an age file, binary or
+//! armored. The result can be displayed as text, or serialized as JSON for scripting.
+
+use std::{
+    fmt,
+    io::{self, Read},
+};
+
+use age_core::format::Stanza;
+use base64::{engine::general_purpose::STANDARD_NO_PAD as BASE64, Engine};
+use serde::Serialize;
+
+use crate::{
+    agile::{AeadAlg, KdfAlg, KemAlg},
+    fingerprint_to_string,
+    internal::{self, HpkeStanza, Identity, Recipient},
+};
+
+// age files start with this line, or are armored
+const AGE_HEADER_VERSION: &str = "age-encryption.org/v1";
+const ARMORED_BEGIN_MARKER: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+// Base64 stanza bodies are wrapped at this number of columns
+const STANZA_BODY_COLUMNS: usize = 64;
+
+const KEMS: [KemAlg; 6] = [
+    KemAlg::X25519HkdfSha256,
+    KemAlg::X25519Kyber768Draft00,
+    KemAlg::X448HkdfSha512,
+    KemAlg::DhP256HkdfSha256,
+    KemAlg::DhP384HkdfSha384,
+    KemAlg::DhP521HkdfSha512,
+];
+
+#[derive(Debug, Serialize)]
+pub struct Algorithm {
+    pub name: &'static str,
+    pub id: u16,
+}
+
+impl From<&KemAlg> for Algorithm {
+    fn from(alg: &KemAlg) -> Self {
+        Self {
+            name: alg.name(),
+            id: alg.to_u16(),
+        }
+    }
+}
+
+impl From<&KdfAlg> for Algorithm {
+    fn from(alg: &KdfAlg) -> Self {
+        Self {
+            name: alg.name(),
+            id: alg.to_u16(),
+        }
+    }
+}
+
+impl From<&AeadAlg> for Algorithm {
+    fn from(alg: &AeadAlg) -> Self {
+        Self {
+            name: alg.name(),
+            id: alg.to_u16(),
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (0x{:04x})", self.name, self.id)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Suite {
+    pub kem: Algorithm,
+    pub kdf: Algorithm,
+    pub aead: Algorithm,
+}
+
+impl Suite {
+    fn new(kem: &KemAlg, kdf: &KdfAlg, aead: &AeadAlg) -> Self {
+        Self {
+            kem: kem.into(),
+            kdf: kdf.into(),
+            aead: aead.into(),
+        }
+    }
+}
+
+/// Associated data, displayed as text when it is valid UTF-8.
+#[derive(Debug, Serialize)]
+pub struct AssociatedData {
+    pub base64: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl From<&[u8]> for AssociatedData {
+    fn from(data: &[u8]) -> Self {
+        Self {
+            base64: BASE64.encode(data),
+            text: String::from_utf8(data.to_vec()).ok(),
+        }
+    }
+}
+
+impl fmt::Display for AssociatedData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.text {
+            Some(text) => write!(f, "{text:?}"),
+            None => write!(f, "{} (base64)", self.base64),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Inspection {
+    Recipient {
+        suite: Suite,
+        public_key_len: usize,
+        associated_data: AssociatedData,
+        fingerprint: String,
+    },
+    CompactRecipient {
+        key_hash: String,
+    },
+    Identity {
+        suite: Suite,
+        private_key_len: usize,
+        public_key_len: usize,
+        associated_data: AssociatedData,
+        fingerprint: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        created: Option<String>,
+    },
+    EncryptedIdentity {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        created: Option<String>,
+    },
+    Stanza {
+        associated_data: AssociatedData,
+        encapped_key_len: usize,
+        /// KEMs whose encapsulated keys have the length found in the stanza
+        kems: Vec<Algorithm>,
+        tag_len: usize,
+        body_len: usize,
+    },
+    ExportStanza {
+        associated_data: AssociatedData,
+        encapped_key_len: usize,
+        /// KEMs whose encapsulated keys have the length found in the stanza
+        kems: Vec<Algorithm>,
+        body_len: usize,
+    },
+}
+
+fn invalid_key(e: impl fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn inspect_recipient(recipient: &[u8]) -> io::Result<Inspection> {
+    if internal::is_compact_recipient(recipient) {
+        return Ok(Inspection::CompactRecipient {
+            key_hash: BASE64.encode(&recipient[1..]),
+        });
+    }
+    let recipient = Recipient::from_bytes(recipient).map_err(invalid_key)?;
+    Ok(Inspection::Recipient {
+        suite: Suite::new(recipient.kem(), recipient.kdf(), recipient.aead()),
+        public_key_len: recipient.public_key().to_bytes().len(),
+        associated_data: recipient.associated_data().into(),
+        fingerprint: fingerprint_to_string(&recipient.fingerprint()),
+    })
+}
+
+fn inspect_identity(identity: &[u8], created: Option<String>) -> io::Result<Inspection> {
+    if internal::is_encrypted_identity(identity) {
+        return Ok(Inspection::EncryptedIdentity { created });
+    }
+    let identity = Identity::from_bytes(identity).map_err(invalid_key)?;
+    let recipient = Recipient::try_from(identity.clone()).map_err(invalid_key)?;
+    Ok(Inspection::Identity {
+        suite: Suite::new(identity.kem(), identity.kdf(), identity.aead()),
+        private_key_len: identity.private_key().to_bytes().len(),
+        public_key_len: recipient.public_key().to_bytes().len(),
+        associated_data: identity.associated_data().into(),
+        fingerprint: fingerprint_to_string(&recipient.fingerprint()),
+        created,
+    })
+}
+
+fn inspect_stanza(stanza: &Stanza) -> Option<io::Result<Inspection>> {
+    let stanza = match HpkeStanza::from_stanza(stanza)? {
+        Ok(stanza) => stanza,
+        Err(_) => {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "hpke stanza has invalid arguments",
+            )))
+        }
+    };
+    let associated_data = stanza.associated_data().into();
+    let encapped_key_len = stanza.encapped_key().len();
+    let kems = KEMS
+        .iter()
+        .filter(|kem| kem.encapped_key_len() == encapped_key_len)
+        .map(Into::into)
+        .collect();
+    Some(Ok(match stanza {
+        HpkeStanza::Sealed {
+            tag, ciphertext, ..
+        } => Inspection::Stanza {
+            associated_data,
+            encapped_key_len,
+            kems,
+            tag_len: tag.len(),
+            body_len: ciphertext.len(),
+        },
+        HpkeStanza::Exported {
+            wrapped_file_key, ..
+        } => Inspection::ExportStanza {
+            associated_data,
+            encapped_key_len,
+            kems,
+            body_len: wrapped_file_key.len(),
+        },
+    }))
+}
+
+// Parse the header of a binary age file, and inspect its hpke stanzas
+fn inspect_header(data: &[u8]) -> io::Result<Vec<Inspection>> {
+    let invalid_header = || io::Error::new(io::ErrorKind::InvalidData, "invalid age header");
+
+    let mut inspections = vec![];
+    // The header ends with the MAC line, the binary payload that follows is not inspected
+    let mut lines = data.split(|b| *b == b'\n').skip(1).map(std::str::from_utf8);
+    while let Some(line) = lines.next() {
+        let line = line.map_err(|_| invalid_header())?;
+        if line.starts_with("---") {
+            return Ok(inspections);
+        }
+        let mut args = line
+            .strip_prefix("-> ")
+            .ok_or_else(invalid_header)?
+            .split(' ');
+        let tag = args.next().ok_or_else(invalid_header)?;
+        let args: Vec<&str> = args.collect();
+
+        let mut body = String::new();
+        loop {
+            let line = lines
+                .next()
+                .ok_or_else(invalid_header)?
+                .map_err(|_| invalid_header())?;
+            body.push_str(line);
+            if line.len() < STANZA_BODY_COLUMNS {
+                break;
+            }
+        }
+
+        let stanza = Stanza {
+            tag: tag.to_owned(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            body: BASE64.decode(body).map_err(|_| invalid_header())?,
+        };
+        if let Some(inspection) = inspect_stanza(&stanza) {
+            inspections.push(inspection?);
+        }
+    }
+    Err(invalid_header())
+}
+
+/// Inspect an identity file, a list of recipients, or an age file.
+pub fn inspect(input: &[u8]) -> io::Result<Vec<Inspection>> {
+    if input.starts_with(ARMORED_BEGIN_MARKER.as_bytes()) {
+        let mut data = vec![];
+        age::armor::ArmoredReader::new(input).read_to_end(&mut data)?;
+        return inspect_header(&data);
+    }
+    if input.starts_with(AGE_HEADER_VERSION.as_bytes()) {
+        return inspect_header(input);
+    }
+
+    let text = std::str::from_utf8(input)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "input is not an age file"))?;
+    let mut inspections = vec![];
+    let mut created = None;
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(comment) = line.strip_prefix('#') {
+            if let Some(date) = comment.trim().strip_prefix("created:") {
+                created = Some(date.trim().to_owned());
+            }
+            continue;
+        }
+        let (hrp, bytes) = crate::encoding::decode(line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        if hrp.starts_with(crate::PLUGIN_IDENTITY_PREFIX) {
+            inspections.push(inspect_identity(&bytes, created.take())?);
+        } else {
+            inspections.push(inspect_recipient(&bytes)?);
+        }
+    }
+    Ok(inspections)
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Recipient {
+                suite,
+                public_key_len,
+                associated_data,
+                fingerprint,
+            } => {
+                writeln!(f, "recipient")?;
+                write!(f, "{suite}")?;
+                writeln!(f, "  public key: {public_key_len} bytes")?;
+                writeln!(f, "  associated data: {associated_data}")?;
+                write!(f, "  fingerprint: {fingerprint}")
+            }
+            Self::CompactRecipient { key_hash } => {
+                writeln!(f, "compact recipient")?;
+                write!(f, "  key hash: {key_hash}")
+            }
+            Self::Identity {
+                suite,
+                private_key_len,
+                public_key_len,
+                associated_data,
+                fingerprint,
+                created,
+            } => {
+                writeln!(f, "identity")?;
+                write!(f, "{suite}")?;
+                writeln!(f, "  private key: {private_key_len} bytes")?;
+                writeln!(f, "  public key: {public_key_len} bytes")?;
+                writeln!(f, "  associated data: {associated_data}")?;
+                write!(f, "  fingerprint: {fingerprint}")?;
+                if let Some(created) = created {
+                    write!(f, "\n  created: {created}")?;
+                }
+                Ok(())
+            }
+            Self::EncryptedIdentity { created } => {
+                write!(f, "passphrase protected identity")?;
+                if let Some(created) = created {
+                    write!(f, "\n  created: {created}")?;
+                }
+                Ok(())
+            }
+            Self::Stanza {
+                associated_data,
+                encapped_key_len,
+                kems,
+                tag_len,
+                body_len,
+            } => {
+                writeln!(f, "hpke stanza")?;
+                writeln!(f, "  associated data: {associated_data}")?;
+                write!(f, "  encapsulated key: {encapped_key_len} bytes")?;
+                let kems: Vec<String> = kems.iter().map(ToString::to_string).collect();
+                if !kems.is_empty() {
+                    write!(f, ", {}", kems.join(" or "))?;
+                }
+                writeln!(f)?;
+                writeln!(f, "  tag: {tag_len} bytes")?;
+                write!(f, "  body: {body_len} bytes")
+            }
+            Self::ExportStanza {
+                associated_data,
+                encapped_key_len,
+                kems,
+                body_len,
+            } => {
+                writeln!(f, "hpke-export stanza")?;
+                writeln!(f, "  associated data: {associated_data}")?;
+                write!(f, "  encapsulated key: {encapped_key_len} bytes")?;
+                let kems: Vec<String> = kems.iter().map(ToString::to_string).collect();
+                if !kems.is_empty() {
+                    write!(f, ", {}", kems.join(" or "))?;
+                }
+                writeln!(f)?;
+                write!(f, "  body: {body_len} bytes")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Suite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  kem: {}", self.kem)?;
+        writeln!(f, "  kdf: {}", self.kdf)?;
+        writeln!(f, "  aead: {}", self.aead)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agile::supported_suites, new_identity, new_identity_to_string, recipient_to_string,
+    };
+
+    #[cfg(all(feature = "nist-p256", feature = "aes-gcm"))]
+    #[test]
+    fn inspect_identity_file() {
+        let (identity, recipient) = new_identity(
+            KemAlg::DhP256HkdfSha256,
+            AeadAlg::AesGcm128,
+            KdfAlg::HkdfSha256,
+            "me",
+        )
+        .unwrap();
+        let identity_file = new_identity_to_string("hpke", &identity, &recipient).unwrap();
+
+        let inspections = inspect(identity_file.as_bytes()).unwrap();
+        let [Inspection::Identity { suite, public_key_len, associated_data, created, .. }] =
+            &inspections[..]
+        else {
+            panic!("expected an identity, got {inspections:?}");
+        };
+        assert_eq!(suite.kem.id, 0x10);
+        assert_eq!(suite.kdf.id, 0x01);
+        assert_eq!(suite.aead.id, 0x01);
+        assert_eq!(*public_key_len, 65);
+        assert_eq!(associated_data.text.as_deref(), Some("me"));
+        assert!(created.is_some());
+    }
+
+    #[test]
+    fn inspect_recipient_matches_identity() {
+        let (aead, kdf, kem) = supported_suites().remove(0);
+        let (identity, recipient) = new_identity(kem, aead, kdf, "").unwrap();
+        let identity_file = new_identity_to_string("hpke", &identity, &recipient).unwrap();
+        let recipient = recipient_to_string("hpke", &recipient).unwrap();
+
+        let from_identity = inspect(identity_file.as_bytes()).unwrap();
+        let from_recipient = inspect(recipient.as_bytes()).unwrap();
+        let (
+            [Inspection::Identity { fingerprint: expected, .. }],
+            [Inspection::Recipient { fingerprint, .. }],
+        ) = (&from_identity[..], &from_recipient[..])
+        else {
+            panic!("unexpected inspection");
+        };
+        assert_eq!(fingerprint, expected);
+    }
+
+    #[test]
+    fn inspect_empty() {
+        assert!(inspect(b"").unwrap().is_empty());
+        assert!(inspect(b"# comment").unwrap().is_empty());
+    }
+
+    #[cfg(all(feature = "x25519", feature = "aes-gcm"))]
+    #[test]
+    fn inspect_age_file() {
+        use std::io::Write;
+
+        use age::armor::Format;
+
+        let (_, recipient) = new_identity(
+            KemAlg::X25519HkdfSha256,
+            AeadAlg::AesGcm256,
+            KdfAlg::HkdfSha256,
+            "ad",
+        )
+        .unwrap();
+        let recipient = Recipient::from_bytes(&recipient).unwrap();
+
+        let mut encrypted = vec![];
+        let encryptor = age::Encryptor::with_recipients(vec![Box::new(recipient.clone())]).unwrap();
+        let mut writer = encryptor.wrap_output(&mut encrypted).unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.finish().unwrap();
+
+        let inspections = inspect(&encrypted).unwrap();
+        assert_eq!(inspections.len(), 1);
+
+        let mut armored = vec![];
+        let encryptor = age::Encryptor::with_recipients(vec![Box::new(recipient)]).unwrap();
+        let output = age::armor::ArmoredWriter::wrap_output(&mut armored, Format::AsciiArmor);
+        let mut writer = encryptor.wrap_output(output.unwrap()).unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.finish().and_then(|armor| armor.finish()).unwrap();
+
+        let inspections = inspect(&armored).unwrap();
+        let [Inspection::Stanza { associated_data, encapped_key_len, kems, tag_len, body_len }] =
+            &inspections[..]
+        else {
+            panic!("expected a stanza, got {inspections:?}");
+        };
+        assert_eq!(associated_data.text.as_deref(), Some("ad"));
+        assert_eq!(*encapped_key_len, 32);
+        assert_eq!(kems[0].id, 0x20);
+        assert_eq!(*tag_len, 16);
+        assert_eq!(*body_len, 16);
+    }
+}
@@ -1,21 +1,238 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read},
+};
 
-use age_core::{format::Stanza, secrecy::ExposeSecret};
-use age_plugin::{identity, recipient};
+use age_core::{
+    format::{FileKey, Stanza},
+    primitives::{aead_decrypt, aead_encrypt},
+    secrecy::{ExposeSecret, SecretString},
+};
+use age_plugin::{identity, recipient, Callbacks};
 use base64::{engine::general_purpose::STANDARD_NO_PAD as BASE64, Engine};
-use bincode::{config, Decode, Encode};
-use rand::{rngs::StdRng, SeedableRng};
+use bincode::{config, enc::write::SizeWriter, error::DecodeError, Decode, Encode};
+use hpke_pq::HpkeError;
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
+use sha2::{Digest, Sha256};
+use zeroize::Zeroizing;
 
-use crate::agile::{
-    agile_setup_receiver, agile_setup_sender, AeadAlg, AgileAeadTag, AgileEncappedKey,
-    AgileKeypair, AgileOpModeR, AgileOpModeRTy, AgileOpModeS, AgileOpModeSTy, AgilePrivateKey,
-    AgilePublicKey, KdfAlg, KemAlg,
+use crate::{
+    agile::{
+        agile_setup_receiver, agile_setup_sender, is_supported_suite, AeadAlg, AgileAeadCtxR,
+        AgileAeadCtxS, AgileAeadTag, AgileEncappedKey, AgileHpkeError, AgileKeypair, AgileOpModeR,
+        AgileOpModeRTy, AgileOpModeS, AgileOpModeSTy, AgilePrivateKey, AgilePublicKey, KdfAlg,
+        KemAlg,
+    },
+    keydir::KeyDirectory,
+    Error,
 };
 
 pub const STANZA_TAG: &str = "hpke";
+pub const EXPORT_STANZA_TAG: &str = "hpke-export";
 pub const INFO_STR: &[u8] = b"age-plugin-hpke";
+// Exporter context of the key wrapping the file key, followed by the associated data
+pub const EXPORT_CONTEXT: &[u8] = b"age-plugin-hpke file key";
+const FILE_KEY_LEN: usize = 16;
+const WRAP_KEY_LEN: usize = 32;
+// Compact recipients are this tag followed by the key hash of the full recipient. A bincode
+// encoded Recipient never starts with it, as it is not a valid variant index.
+pub const COMPACT_RECIPIENT_TAG: u8 = 0xff;
+// Passphrase protected identities are binary age files, which always start with this header
+pub const ENCRYPTED_IDENTITY_PREFIX: &[u8] = b"age-encryption.org/v1\n";
 
-#[derive(Debug, Encode, Decode, PartialEq, Clone)]
+/// Warning shown to the user when a key relies on a suite that is not expected to be stable.
+fn deprecation_warning(kem: &KemAlg) -> Option<String> {
+    match kem {
+        KemAlg::X25519Kyber768Draft00 => Some(
+            "X25519Kyber768Draft00 is based on a draft specification and is deprecated. \
+             Consider generating a new key."
+                .to_owned(),
+        ),
+        _ => None,
+    }
+}
+
+fn export_context(associated_data: &[u8]) -> Vec<u8> {
+    [EXPORT_CONTEXT, associated_data].concat()
+}
+
+/// Stanzas produced by the plugin, all their arguments are base64 encoded with no padding.
+#[derive(Debug, PartialEq)]
+pub enum HpkeStanza {
+    /// `hpke <ASSOCIATED_DATA> <ENCAPPED_KEY> <TAG>`, the body is the file key sealed with the
+    /// HPKE context.
+    Sealed {
+        associated_data: Vec<u8>,
+        encapped_key: Vec<u8>,
+        tag: Vec<u8>,
+        ciphertext: Vec<u8>,
+    },
+    /// `hpke-export <ASSOCIATED_DATA> <ENCAPPED_KEY>`, the body is the file key encrypted with
+    /// ChaCha20Poly1305, keyed with a secret exported from the HPKE context. This is the only
+    /// mode available to the export-only AEAD.
+    Exported {
+        associated_data: Vec<u8>,
+        encapped_key: Vec<u8>,
+        wrapped_file_key: Vec<u8>,
+    },
+}
+
+impl HpkeStanza {
+    /// Returns `None` if the stanza was not produced by the plugin.
+    pub fn from_stanza(stanza: &Stanza) -> Option<Result<Self, age::DecryptError>> {
+        let arg_count = match stanza.tag.as_str() {
+            STANZA_TAG => 3,
+            EXPORT_STANZA_TAG => 2,
+            _ => return None,
+        };
+        if stanza.args.len() != arg_count {
+            return Some(Err(age::DecryptError::InvalidHeader));
+        }
+        let args: Result<Vec<Vec<u8>>, _> =
+            stanza.args.iter().map(|arg| BASE64.decode(arg)).collect();
+        let Ok(mut args) = args else {
+            return Some(Err(age::DecryptError::InvalidHeader));
+        };
+        let body = stanza.body.clone();
+
+        let stanza = if arg_count == 3 {
+            let tag = args.pop().expect("3 arguments");
+            let encapped_key = args.pop().expect("3 arguments");
+            let associated_data = args.pop().expect("3 arguments");
+            Self::Sealed {
+                associated_data,
+                encapped_key,
+                tag,
+                ciphertext: body,
+            }
+        } else {
+            let encapped_key = args.pop().expect("2 arguments");
+            let associated_data = args.pop().expect("2 arguments");
+            Self::Exported {
+                associated_data,
+                encapped_key,
+                wrapped_file_key: body,
+            }
+        };
+        Some(Ok(stanza))
+    }
+
+    pub fn to_stanza(&self) -> Stanza {
+        match self {
+            Self::Sealed {
+                associated_data,
+                encapped_key,
+                tag,
+                ciphertext,
+            } => Stanza {
+                tag: STANZA_TAG.to_string(),
+                args: vec![
+                    BASE64.encode(associated_data),
+                    BASE64.encode(encapped_key),
+                    BASE64.encode(tag),
+                ],
+                body: ciphertext.clone(),
+            },
+            Self::Exported {
+                associated_data,
+                encapped_key,
+                wrapped_file_key,
+            } => Stanza {
+                tag: EXPORT_STANZA_TAG.to_string(),
+                args: vec![BASE64.encode(associated_data), BASE64.encode(encapped_key)],
+                body: wrapped_file_key.clone(),
+            },
+        }
+    }
+
+    pub fn is_exported(&self) -> bool {
+        matches!(self, Self::Exported { .. })
+    }
+
+    pub fn associated_data(&self) -> &[u8] {
+        match self {
+            Self::Sealed {
+                associated_data, ..
+            }
+            | Self::Exported {
+                associated_data, ..
+            } => associated_data,
+        }
+    }
+
+    pub fn encapped_key(&self) -> &[u8] {
+        match self {
+            Self::Sealed { encapped_key, .. } | Self::Exported { encapped_key, .. } => encapped_key,
+        }
+    }
+}
+
+pub fn compact_recipient(recipient: &[u8]) -> Vec<u8> {
+    let mut compact = vec![COMPACT_RECIPIENT_TAG];
+    compact.extend_from_slice(&crate::keydir::key_hash(recipient));
+    compact
+}
+
+pub fn is_compact_recipient(data: &[u8]) -> bool {
+    data.first() == Some(&COMPACT_RECIPIENT_TAG)
+}
+
+// Resolve a compact recipient to the bytes of the full recipient
+pub fn resolve_compact_recipient(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let hash: [u8; 32] = data[1..]
+        .try_into()
+        .map_err(|_| DecodeError::Other("invalid compact recipient"))?;
+    let key_dir = KeyDirectory::from_env().ok_or(Error::NoKeyDirectory)?;
+    key_dir
+        .resolve(&hash)?
+        .ok_or_else(|| Error::UnknownCompactRecipient(key_dir.path().to_path_buf()))
+}
+
+pub fn is_encrypted_identity(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_IDENTITY_PREFIX)
+}
+
+pub fn decrypt_identity(data: &[u8], passphrase: &SecretString) -> Result<Identity, Error> {
+    let decryptor = match age::Decryptor::new(data)? {
+        age::Decryptor::Passphrase(decryptor) => decryptor,
+        age::Decryptor::Recipients(_) => return Err(age::DecryptError::InvalidHeader.into()),
+    };
+    let reader = decryptor.decrypt(passphrase, None)?;
+    // The buffer never grows, growing would leave copies of the identity behind
+    let mut identity = Zeroizing::new(Vec::with_capacity(MAX_KEY_LEN + 1));
+    reader.take(MAX_KEY_LEN as u64).read_to_end(&mut identity)?;
+    Identity::from_bytes(&identity)
+}
+
+// Keys are at most a few kilobytes. The limit bounds allocations when decoding untrusted input.
+const MAX_KEY_LEN: usize = 8192;
+
+fn decode_key<T: Decode>(data: &[u8]) -> Result<T, DecodeError> {
+    let (key, len) =
+        bincode::decode_from_slice(data, config::standard().with_limit::<MAX_KEY_LEN>())?;
+    if len != data.len() {
+        return Err(DecodeError::Other("trailing bytes after key"));
+    }
+    Ok(key)
+}
+
+fn check_suite(kem: &KemAlg, aead: &AeadAlg, kdf: &KdfAlg) -> Result<(), DecodeError> {
+    // Algorithms with a variant are not accepted as `Other`, so that keys have a single encoding
+    let canonical = KemAlg::try_from_u16(kem.to_u16()).ok().as_ref() == Some(kem)
+        && AeadAlg::try_from_u16(aead.to_u16()).ok().as_ref() == Some(aead)
+        && KdfAlg::try_from_u16(kdf.to_u16()).ok().as_ref() == Some(kdf);
+    if canonical && is_supported_suite(aead, kdf, kem) {
+        Ok(())
+    } else {
+        Err(DecodeError::Other("unsupported suite"))
+    }
+}
+
+/// HPKE identity, usable with `age::Decryptor` without going through the plugin protocol.
+///
+/// The private key is redacted from the `Debug` output, and compared in constant time.
+#[derive(Encode, Decode, PartialEq, Clone)]
 pub struct Identity {
     kem: KemAlg,
     aead: AeadAlg,
@@ -24,6 +241,18 @@ pub struct Identity {
     associated_data: Vec<u8>,
 }
 
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("kem", &self.kem)
+            .field("aead", &self.aead)
+            .field("kdf", &self.kdf)
+            .field("private_key", &"<redacted>")
+            .field("associated_data", &self.associated_data)
+            .finish()
+    }
+}
+
 impl Identity {
     pub fn new(
         kem: KemAlg,
@@ -41,13 +270,79 @@ impl Identity {
         }
     }
 
-    pub fn from_bytes(data: &[u8]) -> Self {
-        let (identity, _) = bincode::decode_from_slice(data, config::standard()).unwrap();
+    /// Decode an identity, checking its suite is supported and its private key is valid.
+    ///
+    /// Returns [`Error::InvalidKey`] if it does not decode or is not valid.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let identity: Self = decode_key(data)?;
+        check_suite(&identity.kem, &identity.aead, &identity.kdf)?;
+        if identity.private_key.kem_alg() != &identity.kem {
+            return Err(DecodeError::Other("private key does not match the KEM").into());
+        }
         identity
+            .private_key
+            .validate()
+            .map_err(|_| DecodeError::Other("invalid private key"))?;
+        Ok(identity)
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        bincode::encode_to_vec(self, config::standard()).unwrap()
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        // Encoded into a buffer of the exact size, growing it would leave copies of the private
+        // key behind
+        let mut size = SizeWriter::default();
+        bincode::encode_into_writer(self, &mut size, config::standard()).unwrap();
+        let mut bytes = Zeroizing::new(vec![0u8; size.bytes_written]);
+        bincode::encode_into_slice(self, &mut bytes, config::standard()).unwrap();
+        bytes
+    }
+
+    pub fn kem(&self) -> &KemAlg {
+        &self.kem
+    }
+
+    pub fn aead(&self) -> &AeadAlg {
+        &self.aead
+    }
+
+    pub fn kdf(&self) -> &KdfAlg {
+        &self.kdf
+    }
+
+    pub fn associated_data(&self) -> &[u8] {
+        &self.associated_data
+    }
+
+    pub fn private_key(&self) -> &AgilePrivateKey {
+        &self.private_key
+    }
+
+    // Keys of a KEM that is not registered cannot derive their public key, even when a suite
+    // using the KEM is registered
+    fn keypair(&self) -> Result<AgileKeypair, AgileHpkeError> {
+        self.private_key.validate()?;
+        Ok(AgileKeypair::new(
+            self.private_key.clone(),
+            self.private_key.to_pk()?,
+        ))
+    }
+
+    /// RFC 9180 single-shot `Open` in base mode, of the output of [`Recipient::seal`].
+    pub fn open(&self, info: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        let encapped_key_len = self.kem.encapped_key_len();
+        if data.len() < encapped_key_len {
+            return Err(AgileHpkeError::from(HpkeError::OpenError).into());
+        }
+        let (encapped_key, ciphertext) = data.split_at(encapped_key_len);
+        let mut receiver_ctx = agile_setup_receiver(
+            self.aead.clone(),
+            self.kdf.clone(),
+            self.kem.clone(),
+            &AgileOpModeR::new(self.kem.clone(), AgileOpModeRTy::Base),
+            &self.keypair()?,
+            &AgileEncappedKey::new(self.kem.clone(), encapped_key),
+            info,
+        )?;
+        Ok(receiver_ctx.open(ciphertext, aad)?)
     }
 }
 
@@ -56,44 +351,124 @@ impl age::Identity for Identity {
         &self,
         stanza: &age_core::format::Stanza,
     ) -> Option<Result<age_core::format::FileKey, age::DecryptError>> {
-        if stanza.tag != STANZA_TAG {
-            return None;
-        }
-        // TODO: consider having a helper stanza struct
-        if stanza.args.len() != 3 {
-            return Some(Err(age::DecryptError::InvalidHeader));
-        }
-        let args: [Vec<u8>; 3] = [
-            BASE64.decode(stanza.args[0].clone()).ok()?,
-            BASE64.decode(stanza.args[1].clone()).ok()?,
-            BASE64.decode(stanza.args[2].clone()).ok()?,
-        ];
-        let [associated_data, encapped_key_bytes, tag_bytes] = args;
-
-        let tag: AgileAeadTag = tag_bytes;
-        let encapped_key = AgileEncappedKey::new(self.kem.clone(), &encapped_key_bytes);
+        let stanza = match parse_stanza(stanza, self.aead == AeadAlg::ExportOnlyAead)? {
+            Ok(stanza) => stanza,
+            Err(e) => return Some(Err(e)),
+        };
 
+        // Stanzas wrapped to other recipients fail to open, they are skipped as age does for its
+        // X25519 recipients
+        let encapped_key = AgileEncappedKey::new(self.kem.clone(), stanza.encapped_key());
         let mut receiver_ctx = agile_setup_receiver(
             self.aead.clone(),
             self.kdf.clone(),
             self.kem.clone(),
             &AgileOpModeR::new(self.kem.clone(), AgileOpModeRTy::Base),
-            &AgileKeypair::new(self.private_key.clone(), self.private_key.to_pk()),
+            &self.keypair().ok()?,
             &encapped_key,
             INFO_STR,
         )
-        .expect("invalid credentials!");
+        .ok()?;
+
+        unwrap_with_context(receiver_ctx.as_mut(), stanza).map(Ok)
+    }
+}
 
-        let mut dst = stanza.body.clone();
-        receiver_ctx
-            .open_in_place_detached(&mut dst, &associated_data, &tag)
-            .expect("invalid ciphertext!");
+fn is_hpke_stanza(stanza: &Stanza) -> bool {
+    stanza.tag == STANZA_TAG || stanza.tag == EXPORT_STANZA_TAG
+}
 
-        let file_key: [u8; 16] = dst[..].try_into().ok()?;
-        Some(Ok(file_key.into()))
+// Parses an hpke or hpke-export stanza. Export-only identities only use exported secrets, other
+// identities only seal, stanzas of the other kind are skipped.
+pub(crate) fn parse_stanza(
+    stanza: &Stanza,
+    export_only: bool,
+) -> Option<Result<HpkeStanza, age::DecryptError>> {
+    let stanza = match HpkeStanza::from_stanza(stanza)? {
+        Ok(stanza) => stanza,
+        Err(e) => return Some(Err(e)),
+    };
+    if stanza.is_exported() != export_only {
+        return None;
     }
+    Some(Ok(stanza))
 }
 
+// Unwraps the file key of a stanza with the receiver context of its encapsulated key. Returns None
+// if the stanza was wrapped to another recipient.
+pub(crate) fn unwrap_with_context(
+    receiver_ctx: &mut dyn AgileAeadCtxR,
+    stanza: HpkeStanza,
+) -> Option<FileKey> {
+    let dst = match stanza {
+        HpkeStanza::Sealed {
+            associated_data,
+            tag,
+            ciphertext,
+            ..
+        } => {
+            let tag: AgileAeadTag = tag;
+            let mut dst = ciphertext;
+            receiver_ctx
+                .open_in_place_detached(&mut dst, &associated_data, &tag)
+                .ok()?;
+            dst
+        }
+        HpkeStanza::Exported {
+            associated_data,
+            wrapped_file_key,
+            ..
+        } => {
+            let wrap_key = receiver_ctx
+                .export(&export_context(&associated_data), WRAP_KEY_LEN)
+                .ok()?;
+            let wrap_key: [u8; WRAP_KEY_LEN] = wrap_key[..].try_into().ok()?;
+            aead_decrypt(&wrap_key, FILE_KEY_LEN, &wrapped_file_key).ok()?
+        }
+    };
+
+    let file_key: [u8; FILE_KEY_LEN] = dst[..].try_into().ok()?;
+    Some(file_key.into())
+}
+
+// Wraps a file key with the sender context of an encapsulated key, in an hpke-export stanza for
+// export-only suites and an hpke stanza otherwise
+pub(crate) fn wrap_with_context(
+    sender_ctx: &mut dyn AgileAeadCtxS,
+    export_only: bool,
+    associated_data: &[u8],
+    encapped_key: Vec<u8>,
+    file_key: &FileKey,
+) -> Result<Stanza, age::EncryptError> {
+    let src = file_key.expose_secret().as_slice();
+    let stanza = if export_only {
+        let wrap_key = sender_ctx
+            .export(&export_context(associated_data), WRAP_KEY_LEN)
+            .map_err(encrypt_error)?;
+        let wrap_key: [u8; WRAP_KEY_LEN] = wrap_key[..]
+            .try_into()
+            .expect("export has the requested length");
+        HpkeStanza::Exported {
+            associated_data: associated_data.to_vec(),
+            encapped_key,
+            wrapped_file_key: aead_encrypt(&wrap_key, src),
+        }
+    } else {
+        let mut ciphertext = src.to_vec();
+        let tag = sender_ctx
+            .seal_in_place_detached(&mut ciphertext, associated_data)
+            .map_err(encrypt_error)?;
+        HpkeStanza::Sealed {
+            associated_data: associated_data.to_vec(),
+            encapped_key,
+            tag,
+            ciphertext,
+        }
+    };
+    Ok(stanza.to_stanza())
+}
+
+/// HPKE recipient, usable with `age::Encryptor` without going through the plugin protocol.
 #[derive(Debug, Encode, Decode, PartialEq, Clone)]
 pub struct Recipient {
     kem: KemAlg,
@@ -120,24 +495,89 @@ impl Recipient {
         }
     }
 
-    pub fn from_bytes(data: &[u8]) -> Self {
-        let (recipient, _) = bincode::decode_from_slice(data, config::standard()).unwrap();
+    /// Decode a recipient, checking its suite is supported and its public key is valid.
+    ///
+    /// Returns [`Error::InvalidKey`] if it does not decode or is not valid.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Error> {
+        let recipient: Self = decode_key(data)?;
+        check_suite(&recipient.kem, &recipient.aead, &recipient.kdf)?;
+        if recipient.public_key.kem_alg() != &recipient.kem {
+            return Err(DecodeError::Other("public key does not match the KEM").into());
+        }
         recipient
+            .public_key
+            .validate()
+            .map_err(|_| DecodeError::Other("invalid public key"))?;
+        Ok(recipient)
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
         bincode::encode_to_vec(self, config::standard()).unwrap()
     }
-}
 
-impl age::Recipient for Recipient {
-    fn wrap_file_key(
+    pub fn kem(&self) -> &KemAlg {
+        &self.kem
+    }
+
+    pub fn aead(&self) -> &AeadAlg {
+        &self.aead
+    }
+
+    pub fn kdf(&self) -> &KdfAlg {
+        &self.kdf
+    }
+
+    pub fn associated_data(&self) -> &[u8] {
+        &self.associated_data
+    }
+
+    pub fn public_key(&self) -> &AgilePublicKey {
+        &self.public_key
+    }
+
+    /// SHA-256 over the RFC 9180 suite ids, the public key, and the associated data.
+    ///
+    /// Unlike [`Recipient::to_bytes`], this encoding does not depend on bincode, and is stable.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        let public_key = self.public_key.to_bytes();
+        Sha256::new()
+            .chain_update(self.kem.to_u16().to_be_bytes())
+            .chain_update(self.kdf.to_u16().to_be_bytes())
+            .chain_update(self.aead.to_u16().to_be_bytes())
+            .chain_update((public_key.len() as u32).to_be_bytes())
+            .chain_update(public_key)
+            .chain_update((self.associated_data.len() as u32).to_be_bytes())
+            .chain_update(&self.associated_data)
+            .finalize()
+            .into()
+    }
+
+    /// RFC 9180 single-shot `Seal` in base mode, returning the encapsulated key followed by the
+    /// ciphertext.
+    ///
+    /// The associated data of the recipient is not used, `aad` is.
+    pub fn seal(&self, info: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut csprng = StdRng::from_entropy();
+        let (encapped_key, mut sender_ctx) = agile_setup_sender(
+            self.aead.clone(),
+            self.kdf.clone(),
+            self.kem.clone(),
+            &AgileOpModeS::new(self.kem.clone(), AgileOpModeSTy::Base),
+            &self.public_key,
+            info,
+            &mut csprng,
+        )?;
+        let ciphertext = sender_ctx.seal(plaintext, aad)?;
+        Ok([encapped_key.to_bytes(), ciphertext].concat())
+    }
+
+    /// [`age::Recipient::wrap_file_key`] with the ephemeral key generated from `csprng`, such as
+    /// a seeded RNG in tests or a custom DRBG.
+    pub fn wrap_file_key_with_rng<R: CryptoRng + RngCore>(
         &self,
         file_key: &age_core::format::FileKey,
+        csprng: &mut R,
     ) -> Result<Vec<age_core::format::Stanza>, age::EncryptError> {
-        let src = file_key.expose_secret().as_slice();
-
-        let mut csprng = StdRng::from_entropy();
         let (encapped_key, mut sender_ctx) = agile_setup_sender(
             self.aead.clone(),
             self.kdf.clone(),
@@ -145,42 +585,53 @@ impl age::Recipient for Recipient {
             &AgileOpModeS::new(self.kem.clone(), AgileOpModeSTy::Base),
             &self.public_key,
             INFO_STR,
-            &mut csprng,
+            csprng,
         )
-        .expect("invalid setup");
+        .map_err(encrypt_error)?;
 
-        let mut ciphertext = src.to_vec();
-        let tag = sender_ctx
-            .seal_in_place_detached(&mut ciphertext, &self.associated_data)
-            .expect("encryption failed!");
+        let stanza = wrap_with_context(
+            sender_ctx.as_mut(),
+            self.aead == AeadAlg::ExportOnlyAead,
+            &self.associated_data,
+            encapped_key.to_bytes(),
+            file_key,
+        )?;
+        Ok(vec![stanza])
+    }
+}
 
-        Ok(vec![Stanza {
-            tag: STANZA_TAG.to_string(),
-            args: vec![
-                BASE64.encode(&self.associated_data),
-                BASE64.encode(encapped_key.to_bytes()),
-                BASE64.encode(tag),
-            ],
-            body: ciphertext,
-        }])
+// age only carries I/O errors out of recipients
+pub(crate) fn encrypt_error(e: AgileHpkeError) -> age::EncryptError {
+    io::Error::new(io::ErrorKind::Other, Error::from(e)).into()
+}
+
+impl age::Recipient for Recipient {
+    fn wrap_file_key(
+        &self,
+        file_key: &age_core::format::FileKey,
+    ) -> Result<Vec<age_core::format::Stanza>, age::EncryptError> {
+        self.wrap_file_key_with_rng(file_key, &mut StdRng::from_entropy())
     }
 }
 
-impl From<Identity> for Recipient {
-    fn from(identity: Identity) -> Self {
-        Self {
+impl TryFrom<Identity> for Recipient {
+    type Error = AgileHpkeError;
+
+    fn try_from(identity: Identity) -> Result<Self, Self::Error> {
+        Ok(Self {
+            public_key: identity.private_key.to_pk()?,
             kem: identity.kem,
             aead: identity.aead,
             kdf: identity.kdf,
-            public_key: identity.private_key.to_pk(),
             associated_data: identity.associated_data,
-        }
+        })
     }
 }
 
 pub struct IdentityPlugin {
     plugin_name: String,
     identities: Vec<Identity>,
+    encrypted_identities: Vec<(usize, Vec<u8>)>,
 }
 
 impl IdentityPlugin {
@@ -188,8 +639,47 @@ impl IdentityPlugin {
         Self {
             plugin_name: plugin_name.to_owned(),
             identities: vec![],
+            encrypted_identities: vec![],
+        }
+    }
+
+    // Identities that cannot be unlocked are reported to the user and skipped
+    fn unlock_identities(
+        &mut self,
+        callbacks: &mut impl Callbacks<identity::Error>,
+    ) -> std::io::Result<()> {
+        let encrypted_identities = std::mem::take(&mut self.encrypted_identities);
+        for (index, identity) in unlock_identities(encrypted_identities, callbacks)? {
+            match identity {
+                Ok(identity) => self.identities.push(identity),
+                Err(message) => {
+                    // The error is informative, there is nothing to do if the client cannot
+                    // display it
+                    let _ = callbacks.error(identity::Error::Identity { index, message })?;
+                }
+            }
         }
+        Ok(())
+    }
+}
+
+// Prompts for the passphrase of every encrypted identity, naming it by its index among the
+// identities of the client
+fn unlock_identities<E>(
+    encrypted_identities: Vec<(usize, Vec<u8>)>,
+    callbacks: &mut impl Callbacks<E>,
+) -> std::io::Result<Vec<(usize, Result<Identity, String>)>> {
+    let mut identities = Vec::with_capacity(encrypted_identities.len());
+    for (index, encrypted) in encrypted_identities {
+        let prompt = format!("Enter passphrase for HPKE identity {index}");
+        let identity = match callbacks.request_secret(&prompt)? {
+            Ok(passphrase) => decrypt_identity(&encrypted, &passphrase)
+                .map_err(|e| format!("Failed to unlock identity: {e}")),
+            Err(e) => Err(format!("Failed to request passphrase: {e}")),
+        };
+        identities.push((index, identity));
     }
+    Ok(identities)
 }
 
 impl age_plugin::identity::IdentityPluginV1 for IdentityPlugin {
@@ -200,7 +690,16 @@ impl age_plugin::identity::IdentityPluginV1 for IdentityPlugin {
         bytes: &[u8],
     ) -> Result<(), age_plugin::identity::Error> {
         if plugin_name == self.plugin_name {
-            self.identities.push(Identity::from_bytes(bytes));
+            if is_encrypted_identity(bytes) {
+                self.encrypted_identities.push((index, bytes.to_vec()));
+            } else {
+                let identity =
+                    Identity::from_bytes(bytes).map_err(|e| identity::Error::Identity {
+                        index,
+                        message: format!("Invalid identity: {e}"),
+                    })?;
+                self.identities.push(identity);
+            }
             Ok(())
         } else {
             Err(identity::Error::Identity {
@@ -213,31 +712,46 @@ impl age_plugin::identity::IdentityPluginV1 for IdentityPlugin {
     fn unwrap_file_keys(
         &mut self,
         files: Vec<Vec<age_core::format::Stanza>>,
-        _callbacks: impl age_plugin::Callbacks<age_plugin::identity::Error>,
+        mut callbacks: impl age_plugin::Callbacks<age_plugin::identity::Error>,
     ) -> std::io::Result<
         std::collections::HashMap<
             usize,
             Result<age_core::format::FileKey, Vec<age_plugin::identity::Error>>,
         >,
     > {
+        // Passphrases are only asked for, and warnings only shown, when a file may be wrapped to
+        // the identities
+        if files.iter().flatten().any(is_hpke_stanza) {
+            self.unlock_identities(&mut callbacks)?;
+            for warning in self
+                .identities
+                .iter()
+                .filter_map(|i| deprecation_warning(&i.kem))
+            {
+                let _ = callbacks.message(&warning)?;
+            }
+        }
+
         let mut file_keys = HashMap::with_capacity(files.len());
 
         for (file, stanzas) in files.iter().enumerate() {
-            for (_stanza_index, stanza) in stanzas.iter().enumerate() {
-                if stanza.tag != STANZA_TAG {
-                    continue;
-                }
-                for (_identity_index, identity) in self.identities.iter().enumerate() {
-                    let file_key = age::Identity::unwrap_stanza(identity, stanza).unwrap();
-                    let r = file_key.map_err(|e| {
-                        vec![identity::Error::Identity {
-                            index: file,
-                            message: format!("{e}"),
-                        }]
-                    });
-
-                    file_keys.entry(file).or_insert_with(|| r);
-                }
+            // Files without a stanza for these identities are left out of the result
+            let file_key = stanzas
+                .iter()
+                .filter(|stanza| is_hpke_stanza(stanza))
+                .find_map(|stanza| {
+                    self.identities
+                        .iter()
+                        .find_map(|identity| age::Identity::unwrap_stanza(identity, stanza))
+                });
+            if let Some(file_key) = file_key {
+                let r = file_key.map_err(|e| {
+                    vec![identity::Error::Identity {
+                        index: file,
+                        message: format!("{e}"),
+                    }]
+                });
+                file_keys.insert(file, r);
             }
         }
 
@@ -247,7 +761,9 @@ impl age_plugin::identity::IdentityPluginV1 for IdentityPlugin {
 
 pub struct RecipientPlugin {
     plugin_name: String,
-    identities: Vec<Identity>,
+    // File keys are also wrapped to the recipients of the added identities, by their index
+    identity_recipients: Vec<(usize, Recipient)>,
+    encrypted_identities: Vec<(usize, Vec<u8>)>,
     recipients: Vec<Recipient>,
 }
 
@@ -255,10 +771,56 @@ impl RecipientPlugin {
     pub fn new(plugin_name: &str) -> Self {
         Self {
             plugin_name: plugin_name.to_owned(),
-            identities: vec![],
+            identity_recipients: vec![],
+            encrypted_identities: vec![],
             recipients: vec![],
         }
     }
+
+    fn add_identity_recipient(
+        &mut self,
+        index: usize,
+        identity: Identity,
+    ) -> Result<(), recipient::Error> {
+        let keypair = identity.keypair().map_err(|e| recipient::Error::Identity {
+            index,
+            message: format!("Invalid identity: {e}"),
+        })?;
+        self.identity_recipients.push((
+            index,
+            Recipient::new(
+                identity.kem,
+                identity.aead,
+                identity.kdf,
+                keypair.public_key(),
+                &identity.associated_data,
+            ),
+        ));
+        Ok(())
+    }
+
+    // Unlike decryption, encryption cannot go on without an identity the user passed, so
+    // identities that cannot be unlocked are errors
+    fn unlock_identities(
+        &mut self,
+        callbacks: &mut impl Callbacks<recipient::Error>,
+    ) -> std::io::Result<Result<(), Vec<recipient::Error>>> {
+        let encrypted_identities = std::mem::take(&mut self.encrypted_identities);
+        let mut errors = vec![];
+        for (index, identity) in unlock_identities(encrypted_identities, callbacks)? {
+            let res = identity
+                .map_err(|message| recipient::Error::Identity { index, message })
+                .and_then(|identity| self.add_identity_recipient(index, identity));
+            if let Err(e) = res {
+                errors.push(e);
+            }
+        }
+        Ok(if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        })
+    }
 }
 
 impl age_plugin::recipient::RecipientPluginV1 for RecipientPlugin {
@@ -269,7 +831,20 @@ impl age_plugin::recipient::RecipientPluginV1 for RecipientPlugin {
         bytes: &[u8],
     ) -> Result<(), age_plugin::recipient::Error> {
         if plugin_name == self.plugin_name {
-            self.recipients.push(Recipient::from_bytes(bytes));
+            let recipient = if is_compact_recipient(bytes) {
+                resolve_compact_recipient(bytes).map_err(|e| recipient::Error::Recipient {
+                    index,
+                    message: format!("Cannot resolve compact recipient: {e}"),
+                })?
+            } else {
+                bytes.to_vec()
+            };
+            let recipient =
+                Recipient::from_bytes(&recipient).map_err(|e| recipient::Error::Recipient {
+                    index,
+                    message: format!("Invalid recipient: {e}"),
+                })?;
+            self.recipients.push(recipient);
             Ok(())
         } else {
             Err(recipient::Error::Recipient {
@@ -286,8 +861,17 @@ impl age_plugin::recipient::RecipientPluginV1 for RecipientPlugin {
         bytes: &[u8],
     ) -> Result<(), age_plugin::recipient::Error> {
         if plugin_name == self.plugin_name {
-            self.identities.push(Identity::from_bytes(bytes));
-            Ok(())
+            if is_encrypted_identity(bytes) {
+                self.encrypted_identities.push((index, bytes.to_vec()));
+                Ok(())
+            } else {
+                let identity =
+                    Identity::from_bytes(bytes).map_err(|e| recipient::Error::Identity {
+                        index,
+                        message: format!("Invalid identity: {e}"),
+                    })?;
+                self.add_identity_recipient(index, identity)
+            }
         } else {
             Err(recipient::Error::Recipient {
                 index,
@@ -299,20 +883,382 @@ impl age_plugin::recipient::RecipientPluginV1 for RecipientPlugin {
     fn wrap_file_keys(
         &mut self,
         file_keys: Vec<age_core::format::FileKey>,
-        _callbacks: impl age_plugin::Callbacks<age_plugin::recipient::Error>,
+        mut callbacks: impl age_plugin::Callbacks<age_plugin::recipient::Error>,
     ) -> std::io::Result<
         Result<Vec<Vec<age_core::format::Stanza>>, Vec<age_plugin::recipient::Error>>,
     > {
-        Ok(Ok(file_keys
-            .into_iter()
-            .map(|file_key| {
-                self.recipients
-                    .iter()
-                    .flat_map(|recipient| {
-                        age::Recipient::wrap_file_key(recipient, &file_key).unwrap()
-                    })
-                    .collect()
-            })
-            .collect()))
+        if let Err(errors) = self.unlock_identities(&mut callbacks)? {
+            return Ok(Err(errors));
+        }
+        // The user confirms once, for all the deprecated recipients
+        let mut warnings: Vec<String> = self
+            .recipients
+            .iter()
+            .chain(self.identity_recipients.iter().map(|(_, r)| r))
+            .filter_map(|r| deprecation_warning(&r.kem))
+            .collect();
+        warnings.sort();
+        warnings.dedup();
+        if !warnings.is_empty() {
+            let warning = warnings.join("\n");
+            match callbacks.confirm(&warning, "Encrypt anyway", Some("Cancel"))? {
+                Ok(true) => (),
+                Ok(false) => {
+                    return Ok(Err(vec![recipient::Error::Internal {
+                        message: "Encryption to a deprecated recipient was cancelled".to_owned(),
+                    }]))
+                }
+                // Clients without a UI cannot confirm, the warning is displayed instead
+                Err(_) => {
+                    let _ = callbacks.message(&warning)?;
+                }
+            }
+        }
+
+        let mut wrapped = Vec::with_capacity(file_keys.len());
+        for file_key in &file_keys {
+            let mut stanzas = vec![];
+            for (index, recipient) in self.recipients.iter().enumerate() {
+                match age::Recipient::wrap_file_key(recipient, file_key) {
+                    Ok(recipient_stanzas) => stanzas.extend(recipient_stanzas),
+                    Err(e) => {
+                        return Ok(Err(vec![recipient::Error::Recipient {
+                            index,
+                            message: format!("Cannot wrap file key: {e}"),
+                        }]))
+                    }
+                }
+            }
+            for (index, recipient) in &self.identity_recipients {
+                match age::Recipient::wrap_file_key(recipient, file_key) {
+                    Ok(recipient_stanzas) => stanzas.extend(recipient_stanzas),
+                    Err(e) => {
+                        return Ok(Err(vec![recipient::Error::Identity {
+                            index: *index,
+                            message: format!("Cannot wrap file key: {e}"),
+                        }]))
+                    }
+                }
+            }
+            wrapped.push(stanzas);
+        }
+        Ok(Ok(wrapped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use age::{Identity as _, Recipient as _};
+    use age_core::format::FileKey;
+
+    use super::*;
+    use crate::agile::{agile_gen_keypair, supported_suites};
+
+    #[test]
+    fn wrap_unwrap_every_suite() {
+        let mut csprng = StdRng::from_entropy();
+        for (aead, kdf, kem) in supported_suites() {
+            let keypair = agile_gen_keypair(kem.clone(), &mut csprng).unwrap();
+            let identity = Identity::new(kem, aead, kdf, keypair.private_key(), b"ad");
+            let recipient = Recipient::try_from(identity.clone()).unwrap();
+
+            let file_key = FileKey::from([7u8; 16]);
+            let stanzas = recipient.wrap_file_key(&file_key).unwrap();
+            let unwrapped = identity.unwrap_stanza(&stanzas[0]).unwrap().unwrap();
+            assert_eq!(unwrapped.expose_secret(), file_key.expose_secret());
+        }
+    }
+
+    #[cfg(all(
+        feature = "x25519",
+        feature = "chacha20poly1305",
+        feature = "export-only"
+    ))]
+    #[test]
+    fn export_only_stanza() {
+        let mut csprng = StdRng::from_entropy();
+        let kem = KemAlg::X25519HkdfSha256;
+        let keypair = agile_gen_keypair(kem.clone(), &mut csprng).unwrap();
+        let identity = Identity::new(
+            kem,
+            AeadAlg::ExportOnlyAead,
+            KdfAlg::HkdfSha256,
+            keypair.private_key(),
+            b"ad",
+        );
+        let recipient = Recipient::try_from(identity.clone()).unwrap();
+
+        let file_key = FileKey::from([7u8; 16]);
+        let stanzas = recipient.wrap_file_key(&file_key).unwrap();
+        assert_eq!(stanzas[0].tag, EXPORT_STANZA_TAG);
+        assert_eq!(stanzas[0].args.len(), 2);
+        let stanza = HpkeStanza::from_stanza(&stanzas[0]).unwrap().unwrap();
+        assert!(stanza.is_exported());
+        assert_eq!(stanza.to_stanza().args, stanzas[0].args);
+
+        // A different associated data derives a different wrapping key
+        let tampered = Stanza {
+            tag: stanzas[0].tag.clone(),
+            args: vec![BASE64.encode(b"other"), stanzas[0].args[1].clone()],
+            body: stanzas[0].body.clone(),
+        };
+        assert!(identity.unwrap_stanza(&tampered).is_none());
+
+        // Identities of other AEADs ignore exported stanzas
+        let sealing_identity = Identity::new(
+            identity.kem().clone(),
+            AeadAlg::ChaCha20Poly1305,
+            identity.kdf().clone(),
+            identity.private_key(),
+            b"ad",
+        );
+        assert!(sealing_identity.unwrap_stanza(&stanzas[0]).is_none());
+    }
+
+    #[cfg(all(
+        feature = "x25519",
+        feature = "chacha20poly1305",
+        feature = "export-only"
+    ))]
+    #[test]
+    fn exported_secrets_match() {
+        let mut csprng = StdRng::from_entropy();
+        let kem = KemAlg::X25519HkdfSha256;
+        let keypair = agile_gen_keypair(kem.clone(), &mut csprng).unwrap();
+        for aead in [AeadAlg::ChaCha20Poly1305, AeadAlg::ExportOnlyAead] {
+            let (encapped_key, mut sender_ctx) = agile_setup_sender(
+                aead.clone(),
+                KdfAlg::HkdfSha256,
+                kem.clone(),
+                &AgileOpModeS::new(kem.clone(), AgileOpModeSTy::Base),
+                keypair.public_key(),
+                INFO_STR,
+                &mut csprng,
+            )
+            .unwrap();
+            let receiver_ctx = agile_setup_receiver(
+                aead.clone(),
+                KdfAlg::HkdfSha256,
+                kem.clone(),
+                &AgileOpModeR::new(kem.clone(), AgileOpModeRTy::Base),
+                &keypair,
+                &encapped_key,
+                INFO_STR,
+            )
+            .unwrap();
+
+            let secret = sender_ctx.export(b"context", 42).unwrap();
+            assert_eq!(secret.len(), 42);
+            assert_eq!(receiver_ctx.export(b"context", 42).unwrap(), secret);
+            assert_ne!(receiver_ctx.export(b"other", 42).unwrap(), secret);
+
+            if aead == AeadAlg::ExportOnlyAead {
+                let mut plaintext = b"hello".to_vec();
+                assert!(sender_ctx
+                    .seal_in_place_detached(&mut plaintext, b"")
+                    .is_err());
+            }
+        }
+    }
+
+    #[cfg(all(feature = "x25519", feature = "pq-hybrid", feature = "aes-gcm"))]
+    #[test]
+    fn seal_open() {
+        let mut csprng = StdRng::from_entropy();
+        for kem in [KemAlg::X25519HkdfSha256, KemAlg::X25519Kyber768Draft00] {
+            let keypair = agile_gen_keypair(kem.clone(), &mut csprng).unwrap();
+            let identity = Identity::new(
+                kem.clone(),
+                AeadAlg::AesGcm256,
+                KdfAlg::HkdfSha256,
+                keypair.private_key(),
+                b"",
+            );
+            let recipient = Recipient::try_from(identity.clone()).unwrap();
+
+            let sealed = recipient.seal(b"info", b"aad", b"secret").unwrap();
+            assert_eq!(sealed.len(), kem.encapped_key_len() + 6 + 16);
+            assert_eq!(identity.open(b"info", b"aad", &sealed).unwrap(), b"secret");
+            assert!(identity.open(b"other", b"aad", &sealed).is_err());
+            assert!(identity.open(b"info", b"other", &sealed).is_err());
+            assert!(identity.open(b"info", b"aad", &sealed[..10]).is_err());
+        }
+    }
+
+    #[cfg(all(feature = "x25519", feature = "chacha20poly1305"))]
+    #[test]
+    fn from_bytes_rejects_invalid_keys() {
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agile_gen_keypair(KemAlg::X25519HkdfSha256, &mut csprng).unwrap();
+        let identity = Identity::new(
+            KemAlg::X25519HkdfSha256,
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            keypair.private_key(),
+            b"",
+        );
+        let recipient = Recipient::try_from(identity.clone()).unwrap();
+        assert_eq!(
+            Identity::from_bytes(&identity.to_bytes()).unwrap(),
+            identity
+        );
+        assert_eq!(
+            Recipient::from_bytes(&recipient.to_bytes()).unwrap(),
+            recipient
+        );
+
+        let mut trailing = identity.to_bytes();
+        trailing.push(0);
+        assert!(Identity::from_bytes(&trailing).is_err());
+
+        // Keys of unsupported or mismatched KEMs
+        let public_key = AgilePublicKey::new(KemAlg::X448HkdfSha512, &[1u8; 56]);
+        let x448 = Recipient::new(
+            KemAlg::X448HkdfSha512,
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha512,
+            &public_key,
+            b"",
+        );
+        assert!(Recipient::from_bytes(&x448.to_bytes()).is_err());
+        let mismatched = Recipient::new(
+            KemAlg::X25519Kyber768Draft00,
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            recipient.public_key(),
+            b"",
+        );
+        assert!(Recipient::from_bytes(&mismatched.to_bytes()).is_err());
+        let other_key =
+            AgilePublicKey::new(KemAlg::Other(0x20), &recipient.public_key().to_bytes());
+        let other = Recipient::new(
+            KemAlg::Other(0x20),
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            &other_key,
+            b"",
+        );
+        assert!(Recipient::from_bytes(&other.to_bytes()).is_err());
+
+        let short_key = AgilePublicKey::new(KemAlg::X25519HkdfSha256, &[1u8; 31]);
+        let short = Recipient::new(
+            KemAlg::X25519HkdfSha256,
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            &short_key,
+            b"",
+        );
+        assert!(Recipient::from_bytes(&short.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn disabled_kem_is_an_error() {
+        use crate::agile::agile_derive_keypair;
+
+        let kem = KemAlg::X448HkdfSha512;
+        assert!(agile_gen_keypair(kem.clone(), &mut StdRng::from_entropy()).is_err());
+        assert!(agile_derive_keypair(kem.clone(), &[1u8; 56]).is_err());
+        let private_key = AgilePrivateKey::new(kem.clone(), &[1u8; 56]);
+        assert!(private_key.to_pk().is_err());
+        let identity = Identity::new(
+            kem,
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha512,
+            &private_key,
+            b"",
+        );
+        assert!(Recipient::try_from(identity).is_err());
+    }
+
+    #[cfg(all(feature = "x25519", feature = "chacha20poly1305"))]
+    #[test]
+    fn debug_redacts_private_key() {
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agile_gen_keypair(KemAlg::X25519HkdfSha256, &mut csprng).unwrap();
+        let identity = Identity::new(
+            KemAlg::X25519HkdfSha256,
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            keypair.private_key(),
+            b"",
+        );
+        let private_key = format!("{:?}", &*keypair.private_key().to_bytes());
+        for debug in [
+            format!("{:?}", identity),
+            format!("{:#?}", identity),
+            format!("{:?}", keypair.private_key()),
+        ] {
+            assert!(debug.contains("<redacted>"));
+            assert!(!debug.contains(&private_key));
+        }
+    }
+
+    #[cfg(all(feature = "x25519", feature = "chacha20poly1305"))]
+    #[test]
+    fn private_key_equality() {
+        use crate::agile::agile_derive_keypair;
+
+        let keypair = agile_derive_keypair(KemAlg::X25519HkdfSha256, &[1u8; 32]).unwrap();
+        let same = agile_derive_keypair(KemAlg::X25519HkdfSha256, &[1u8; 32]).unwrap();
+        let other = agile_derive_keypair(KemAlg::X25519HkdfSha256, &[2u8; 32]).unwrap();
+        assert_eq!(keypair.private_key(), same.private_key());
+        assert_ne!(keypair.private_key(), other.private_key());
+
+        let identity = |private_key| {
+            Identity::new(
+                KemAlg::X25519HkdfSha256,
+                AeadAlg::ChaCha20Poly1305,
+                KdfAlg::HkdfSha256,
+                private_key,
+                b"",
+            )
+        };
+        assert_eq!(
+            identity(keypair.private_key()),
+            identity(same.private_key())
+        );
+        assert_ne!(
+            identity(keypair.private_key()),
+            identity(other.private_key())
+        );
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        let public_key = AgilePublicKey::new(KemAlg::X25519HkdfSha256, &[1u8; 32]);
+        let recipient = Recipient::new(
+            KemAlg::X25519HkdfSha256,
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            &public_key,
+            b"user@example.com",
+        );
+        assert_eq!(
+            BASE64.encode(recipient.fingerprint()),
+            "aWKSrwarTvFY4Kl35g8jGBVgPUafop/dIFAYNqwvlFM"
+        );
+    }
+
+    #[test]
+    fn fingerprint_depends_on_suite() {
+        let public_key = AgilePublicKey::new(KemAlg::X25519HkdfSha256, &[1u8; 32]);
+        let recipient = |aead: AeadAlg, associated_data: &[u8]| {
+            Recipient::new(
+                KemAlg::X25519HkdfSha256,
+                aead,
+                KdfAlg::HkdfSha256,
+                &public_key,
+                associated_data,
+            )
+        };
+        let fingerprint = recipient(AeadAlg::AesGcm128, b"").fingerprint();
+        assert_ne!(
+            fingerprint,
+            recipient(AeadAlg::AesGcm256, b"").fingerprint()
+        );
+        assert_ne!(
+            fingerprint,
+            recipient(AeadAlg::AesGcm128, b"ad").fingerprint()
+        );
     }
 }
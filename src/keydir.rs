@@ -0,0 +1,172 @@
+//! Key directory used to resolve compact recipients.
+//!
+//! Post-quantum recipients are larger than a kilobyte. A compact recipient only contains the
+//! SHA-256 hash of the full recipient, and the plugin looks the full recipient up in a key
+//! directory when encrypting.
+//!
+//! The key directory is either a file, or a directory of files. Each file lists full recipients,
+//! one per line. Empty lines and lines starting with `#` are ignored.
+
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::encoding;
+
+/// Environment variable overriding the default key directory location.
+pub const KEY_DIR_ENV: &str = "AGE_PLUGIN_HPKE_KEY_DIR";
+
+/// Hash identifying a recipient, computed over its bytes.
+pub fn key_hash(recipient: &[u8]) -> [u8; 32] {
+    Sha256::digest(recipient).into()
+}
+
+pub struct KeyDirectory {
+    path: PathBuf,
+}
+
+impl KeyDirectory {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Key directory from [`KEY_DIR_ENV`], defaulting to `age-plugin-hpke/recipients` in the
+    /// user configuration directory.
+    pub fn from_env() -> Option<Self> {
+        if let Some(path) = env::var_os(KEY_DIR_ENV) {
+            return Some(Self::new(path));
+        }
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(Self::new(
+            config_dir.join("age-plugin-hpke").join("recipients"),
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Find the full recipient whose [`key_hash`] is `hash`.
+    ///
+    /// Lines which are not bech32 encoded are skipped, as are files which cannot be read, such as
+    /// editor swap files. A missing key directory resolves nothing.
+    pub fn resolve(&self, hash: &[u8; 32]) -> io::Result<Option<Vec<u8>>> {
+        for file in self.files()? {
+            let Ok(content) = fs::read(file) else {
+                continue;
+            };
+            // Invalid UTF-8 only spoils the lines it is on
+            let content = String::from_utf8_lossy(&content);
+            let recipients = content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter_map(|line| encoding::decode(line).ok())
+                .map(|(_, recipient)| recipient);
+            for recipient in recipients {
+                if &key_hash(&recipient) == hash {
+                    return Ok(Some(recipient));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn files(&self) -> io::Result<Vec<PathBuf>> {
+        if self.path.is_file() {
+            return Ok(vec![self.path.clone()]);
+        }
+        if !self.path.is_dir() {
+            return Ok(vec![]);
+        }
+        let mut files = vec![];
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+        // Resolution does not depend on the order the filesystem lists files in
+        files.sort();
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("age-plugin-hpke-{}-{}", name, std::process::id()))
+    }
+
+    fn recipient_line(recipient: &[u8]) -> String {
+        encoding::encode("age1hpke", recipient).unwrap()
+    }
+
+    #[test]
+    fn resolve_from_file() {
+        let path = temp_path("keydir-file");
+        fs::write(
+            &path,
+            format!(
+                "# recipients\n\n{}\nnot a recipient\n{}\n",
+                recipient_line(b"first"),
+                recipient_line(b"second"),
+            ),
+        )
+        .unwrap();
+
+        let key_dir = KeyDirectory::new(&path);
+        let resolved = key_dir.resolve(&key_hash(b"second")).unwrap();
+        let missing = key_dir.resolve(&key_hash(b"third")).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(resolved, Some(b"second".to_vec()));
+        assert_eq!(missing, None);
+    }
+
+    #[test]
+    fn resolve_from_directory() {
+        let path = temp_path("keydir-dir");
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("a.pub"), recipient_line(b"first")).unwrap();
+        fs::write(path.join("b.pub"), recipient_line(b"second")).unwrap();
+
+        let key_dir = KeyDirectory::new(&path);
+        let resolved = key_dir.resolve(&key_hash(b"second")).unwrap();
+        fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(resolved, Some(b"second".to_vec()));
+    }
+
+    #[test]
+    fn resolve_skips_binary_files() {
+        let path = temp_path("keydir-binary");
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("a.pub.swp"), [0xff, 0xfe, 0x00, b'\n', 0xc3]).unwrap();
+        let mut mixed = vec![0xff, b'\n'];
+        mixed.extend(recipient_line(b"second").as_bytes());
+        fs::write(path.join("b.pub"), mixed).unwrap();
+
+        let key_dir = KeyDirectory::new(&path);
+        let resolved = key_dir.resolve(&key_hash(b"second")).unwrap();
+        fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(resolved, Some(b"second".to_vec()));
+    }
+
+    #[test]
+    fn resolve_missing_directory() {
+        let key_dir = KeyDirectory::new(temp_path("keydir-missing"));
+        assert_eq!(key_dir.resolve(&key_hash(b"first")).unwrap(), None);
+    }
+}
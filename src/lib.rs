@@ -1,15 +1,41 @@
-use std::io::{self};
+use std::io::{self, Write};
 
-use agile::{agile_gen_keypair, AeadAlg, KemAlg};
+use age_core::secrecy::SecretString;
+use agile::{agile_gen_keypair, is_supported_suite, AeadAlg, KdfAlg, KemAlg};
+use base64::{engine::general_purpose::STANDARD_NO_PAD as BASE64, Engine};
 
-use bech32::{ToBase32, Variant};
-use internal::{Identity, Recipient};
-use rand::{rngs::StdRng, SeedableRng};
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
 
 use crate::internal::{IdentityPlugin, RecipientPlugin};
 
+#[cfg(not(any(
+    feature = "x25519",
+    feature = "nist-p256",
+    feature = "nist-p384",
+    feature = "pq-hybrid"
+)))]
+compile_error!("at least one KEM feature must be enabled: x25519, nist-p256, nist-p384, pq-hybrid");
+
+#[cfg(not(any(
+    feature = "aes-gcm",
+    feature = "chacha20poly1305",
+    feature = "export-only"
+)))]
+compile_error!("at least one AEAD feature must be enabled: aes-gcm, chacha20poly1305, export-only");
+
 pub mod agile;
+mod backend;
+pub mod encoding;
+mod error;
+pub mod inspect;
 mod internal;
+pub mod keydir;
+pub mod typed;
+
+pub use bincode::error::DecodeError;
+pub use error::Error;
+pub use internal::{Identity, Recipient};
+pub use zeroize::Zeroizing;
 
 // Plugin HRPs are age1[name] and AGE-PLUGIN-[NAME]-
 const PLUGIN_RECIPIENT_PREFIX: &str = "age1";
@@ -23,52 +49,126 @@ pub fn run_state_machine(plugin_name: &str, state_machine: &str) -> io::Result<(
     )
 }
 
-pub fn new_identity(kem: KemAlg, aead: AeadAlg, associated_data: &str) -> (Vec<u8>, Vec<u8>) {
-    let mut csprng = StdRng::from_entropy();
-    let keypair = agile_gen_keypair(kem.clone(), &mut csprng);
+/// Generate an identity and its recipient, for any HPKE suite.
+///
+/// The KDF is independent of the KDF the KEM uses internally. Returns
+/// [`Error::UnsupportedSuite`] if the suite is not supported, which can be checked beforehand with
+/// [`agile::is_supported_suite`].
+pub fn new_identity(
+    kem: KemAlg,
+    aead: AeadAlg,
+    kdf: KdfAlg,
+    associated_data: &str,
+) -> Result<(Zeroizing<Vec<u8>>, Vec<u8>), Error> {
+    new_identity_with_rng(kem, aead, kdf, associated_data, &mut StdRng::from_entropy())
+}
+
+/// [`new_identity`] with the key generated from `csprng`, such as a seeded RNG in tests or a
+/// custom DRBG.
+///
+/// Returns [`Error::Encoding`] if the associated data makes the identity or the recipient too
+/// long to encode, even with an empty plugin name.
+pub fn new_identity_with_rng<R: CryptoRng + RngCore>(
+    kem: KemAlg,
+    aead: AeadAlg,
+    kdf: KdfAlg,
+    associated_data: &str,
+    csprng: &mut R,
+) -> Result<(Zeroizing<Vec<u8>>, Vec<u8>), Error> {
+    if !is_supported_suite(&aead, &kdf, &kem) {
+        return Err(Error::UnsupportedSuite { kem, kdf, aead });
+    }
+    let keypair = agile_gen_keypair(kem.clone(), csprng)?;
     let identity = Identity::new(
         kem.clone(),
         aead.clone(),
-        kem.kdf_alg(),
+        kdf.clone(),
         keypair.private_key(),
         associated_data.as_bytes(),
     );
     let recipient = Recipient::new(
-        kem.clone(),
+        kem,
         aead,
-        kem.kdf_alg(),
+        kdf,
         keypair.public_key(),
         associated_data.as_bytes(),
     );
 
-    (identity.to_bytes(), recipient.to_bytes())
+    let (identity, recipient) = (identity.to_bytes(), recipient.to_bytes());
+    check_encoded_len(&format!("{PLUGIN_IDENTITY_PREFIX}-"), &identity)?;
+    check_encoded_len(PLUGIN_RECIPIENT_PREFIX, &recipient)?;
+    Ok((identity, recipient))
+}
+
+// Keys longer than the encoding limit would only fail once encoded, after they are generated
+fn check_encoded_len(hrp: &str, data: &[u8]) -> Result<(), Error> {
+    let len = encoding::encoded_len(hrp, data.len());
+    if len > encoding::MAX_ENCODED_LEN {
+        return Err(encoding::EncodingError::TooLong(len).into());
+    }
+    Ok(())
 }
 
-pub fn new_identity_to_string(plugin_name: &str, identity: &[u8], recipient: &[u8]) -> String {
-    format!(
-        "# created: {}
-# recipient: {}
-{}
-",
+/// Encode an identity file, with the recipient and its fingerprint in comments.
+///
+/// The encoded identity is wiped from memory when the returned string is dropped.
+pub fn new_identity_to_string(
+    plugin_name: &str,
+    identity: &[u8],
+    recipient: &[u8],
+) -> Result<Zeroizing<String>, Error> {
+    let header = format!(
+        "# created: {}\n# fingerprint: {}\n# recipient: {}\n",
         chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
-        recipient_to_string(plugin_name, recipient),
-        identity_to_string(plugin_name, identity),
-    )
+        recipient_fingerprint(recipient)?,
+        recipient_to_string(plugin_name, recipient)?,
+    );
+    let identity = identity_to_string(plugin_name, identity)?;
+    // Allocated once, so no partial copy of the identity is left behind
+    let mut file = Zeroizing::new(String::with_capacity(header.len() + identity.len() + 1));
+    file.push_str(&header);
+    file.push_str(&identity);
+    file.push('\n');
+    Ok(file)
 }
 
-pub fn identity_to_string(plugin_name: &str, identity: &[u8]) -> String {
-    bech32::encode(
-        &format!("{}{}-", PLUGIN_IDENTITY_PREFIX, plugin_name),
-        identity.to_base32(),
-        Variant::Bech32,
-    )
-    .expect("HRP is valid")
-    .to_uppercase()
+/// Protect an identity with a passphrase.
+///
+/// The identity is encrypted to an age scrypt recipient, and can be encoded with
+/// [`identity_to_string`]. The plugin prompts for the passphrase when the identity is used.
+pub fn encrypt_identity(identity: &[u8], passphrase: SecretString) -> Vec<u8> {
+    let mut encrypted = vec![];
+    let mut writer = age::Encryptor::with_user_passphrase(passphrase)
+        .wrap_output(&mut encrypted)
+        .expect("writing to a Vec never fails");
+    writer
+        .write_all(identity)
+        .expect("writing to a Vec never fails");
+    writer.finish().expect("writing to a Vec never fails");
+    encrypted
+}
+
+pub fn is_encrypted_identity(identity: &[u8]) -> bool {
+    internal::is_encrypted_identity(identity)
+}
+
+/// Remove the passphrase protection added by [`encrypt_identity`].
+pub fn decrypt_identity(
+    identity: &[u8],
+    passphrase: &SecretString,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    internal::decrypt_identity(identity, passphrase).map(|identity| identity.to_bytes())
 }
 
-pub fn identity_from_string(identity: &str) -> Vec<u8> {
-    use bech32::FromBase32;
+pub fn identity_to_string(plugin_name: &str, identity: &[u8]) -> Result<Zeroizing<String>, Error> {
+    let identity = Zeroizing::new(encoding::encode(
+        &format!("{}{}-", PLUGIN_IDENTITY_PREFIX, plugin_name),
+        identity,
+    )?);
+    Ok(Zeroizing::new(identity.to_uppercase()))
+}
 
+pub fn identity_from_string(identity: &str) -> Result<Zeroizing<Vec<u8>>, Error> {
     let mut identity = identity.trim();
     while identity.starts_with('#') {
         identity = identity
@@ -78,20 +178,67 @@ pub fn identity_from_string(identity: &str) -> Vec<u8> {
             .unwrap_or("")
             .trim();
     }
-    let (_, identity_decoded, _) = bech32::decode(identity).unwrap();
-    Vec::from_base32(&identity_decoded).unwrap()
+    let (_, identity) = encoding::decode(identity)?;
+    Ok(Zeroizing::new(identity))
 }
 
-pub fn recipient_to_string(plugin_name: &str, recipient: &[u8]) -> String {
-    bech32::encode(
+pub fn recipient_to_string(plugin_name: &str, recipient: &[u8]) -> Result<String, Error> {
+    Ok(encoding::encode(
         &format!("{}{}", PLUGIN_RECIPIENT_PREFIX, plugin_name),
-        recipient.to_base32(),
-        Variant::Bech32,
-    )
-    .expect("HRP is valid")
+        recipient,
+    )?)
+}
+
+pub fn recipient_from_string(recipient: &str) -> Result<Vec<u8>, Error> {
+    let (_, recipient) = encoding::decode(recipient.trim())?;
+    Ok(recipient)
+}
+
+/// Compact form of a recipient, containing the hash of the full recipient.
+///
+/// The plugin resolves compact recipients with the [`keydir::KeyDirectory`] the user configured.
+pub fn compact_recipient(recipient: &[u8]) -> Vec<u8> {
+    internal::compact_recipient(recipient)
+}
+
+/// Full recipient of a compact recipient, looked up in the key directory. Full recipients are
+/// returned as is.
+pub fn resolve_recipient(recipient: &[u8]) -> Result<Vec<u8>, Error> {
+    if internal::is_compact_recipient(recipient) {
+        internal::resolve_compact_recipient(recipient)
+    } else {
+        Ok(recipient.to_vec())
+    }
+}
+
+/// Single-shot HPKE encryption to a recipient, outside of age.
+///
+/// This is RFC 9180 `Seal` in base mode, with the suite and public key of the recipient. The
+/// output is the encapsulated key followed by the ciphertext. The associated data of the recipient
+/// is not used, `aad` is.
+pub fn seal(recipient: &[u8], info: &[u8], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    Recipient::from_bytes(recipient)?.seal(info, aad, plaintext)
+}
+
+/// Decrypt the output of [`seal`] with an identity.
+pub fn open(identity: &[u8], info: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    Identity::from_bytes(identity)?.open(info, aad, data)
+}
+
+/// Format a fingerprint as `SHA256:<base64>`, the way OpenSSH does.
+pub fn fingerprint_to_string(fingerprint: &[u8]) -> String {
+    format!("SHA256:{}", BASE64.encode(fingerprint))
+}
+
+/// Fingerprint of a recipient, to compare keys out-of-band.
+///
+/// The fingerprint covers the suite, the public key, and the associated data.
+pub fn recipient_fingerprint(recipient: &[u8]) -> Result<String, Error> {
+    let recipient = Recipient::from_bytes(recipient)?;
+    Ok(fingerprint_to_string(&recipient.fingerprint()))
 }
 
-pub fn convert_identity_to_recipient(identity: &[u8]) -> Vec<u8> {
-    let recipient: Recipient = Identity::from_bytes(identity).into();
-    recipient.to_bytes()
+pub fn convert_identity_to_recipient(identity: &[u8]) -> Result<Vec<u8>, Error> {
+    let recipient = Recipient::try_from(Identity::from_bytes(identity)?)?;
+    Ok(recipient.to_bytes())
 }
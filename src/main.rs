@@ -1,16 +1,242 @@
-use age_plugin_hpke::{new_identity, new_identity_to_string};
-use cli::{Aead, Kem};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use age_core::secrecy::SecretString;
+use age_plugin_hpke::{
+    agile::supported_suites, compact_recipient, convert_identity_to_recipient, decrypt_identity,
+    encrypt_identity, identity_from_string, is_encrypted_identity, new_identity,
+    new_identity_to_string, recipient_fingerprint, recipient_from_string, recipient_to_string,
+    resolve_recipient, Zeroizing,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use cli::{Command, GenerateArgs, HpkeArgs};
 
 mod cli;
 
 pub const PLUGIN_NAME: &str = "hpke";
 
-pub fn generate(associated_data: &str, kem: Kem, aead: Aead) {
-    let (identity, recipient) = new_identity(kem.to_alg(), aead.to_alg(), associated_data);
-    println!(
-        "{}",
-        new_identity_to_string(PLUGIN_NAME, &identity, &recipient)
+// Passphrases are read from the terminal, which is an error to the user when there is none
+fn prompt_password(prompt: &str) -> String {
+    exit_on_error(rpassword::prompt_password(prompt), "cannot read passphrase")
+}
+
+fn read_passphrase() -> SecretString {
+    SecretString::new(prompt_password("Enter passphrase: "))
+}
+
+fn read_new_passphrase() -> SecretString {
+    let passphrase = prompt_password("Enter passphrase: ");
+    let confirmation = prompt_password("Confirm passphrase: ");
+    if passphrase != confirmation {
+        eprintln!("Passphrases didn't match");
+        std::process::exit(1);
+    }
+    SecretString::new(passphrase)
+}
+
+// Identity files are only readable by their owner, as age-keygen does
+fn create_identity_file(path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+pub fn generate(args: GenerateArgs) {
+    let kem = args.kem.to_alg();
+    let kdf = args.kdf.map_or_else(|| kem.kdf_alg(), |kdf| kdf.to_alg());
+    let aead = args.aead.to_alg();
+    let (identity, recipient) = exit_on_error(
+        new_identity(kem, aead, kdf, &args.associated_data),
+        "cannot generate identity",
+    );
+    let identity = if args.passphrase {
+        Zeroizing::new(encrypt_identity(&identity, read_new_passphrase()))
+    } else {
+        identity
+    };
+    let identity_file = exit_on_error(
+        new_identity_to_string(PLUGIN_NAME, &identity, &recipient),
+        "cannot encode identity",
     );
+    let fingerprint = exit_on_error(recipient_fingerprint(&recipient), "cannot encode recipient");
+    let recipient = exit_on_error(
+        recipient_to_string(PLUGIN_NAME, &recipient),
+        "cannot encode recipient",
+    );
+
+    match args.output {
+        Some(path) => {
+            exit_on_error(
+                create_identity_file(&path)
+                    .and_then(|mut file| file.write_all(identity_file.as_bytes())),
+                "cannot write identity file",
+            );
+            eprintln!("Public key: {recipient}");
+            eprintln!("Fingerprint: {fingerprint}");
+        }
+        None => println!("{}", identity_file.as_str()),
+    }
+    if let Some(path) = args.recipient_output {
+        exit_on_error(
+            fs::write(path, format!("{recipient}\n")),
+            "cannot write recipient file",
+        );
+    }
+}
+
+// Print errors the user can act on, instead of panicking
+fn exit_on_error<T, E: fmt::Display>(result: Result<T, E>, context: &str) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("error: {context}: {e}");
+        std::process::exit(1);
+    })
+}
+
+// Read a file, or standard input if path is "-"
+fn read_input(path: &str) -> io::Result<Vec<u8>> {
+    let mut input = vec![];
+    if path == "-" {
+        io::stdin().read_to_end(&mut input)?;
+    } else {
+        File::open(path)?.read_to_end(&mut input)?;
+    }
+    Ok(input)
+}
+
+// Identity files are a few kilobytes, even with post-quantum keys and a passphrase
+const MAX_IDENTITY_FILE_LEN: usize = 65536;
+
+// Read an identity file, or standard input if path is "-". The buffer never grows, growing would
+// leave copies of the identity behind.
+fn read_secret_input(path: &str) -> io::Result<Zeroizing<Vec<u8>>> {
+    let reader: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    let mut input = Zeroizing::new(Vec::with_capacity(MAX_IDENTITY_FILE_LEN + 1));
+    reader
+        .take(MAX_IDENTITY_FILE_LEN as u64 + 1)
+        .read_to_end(&mut input)?;
+    if input.len() > MAX_IDENTITY_FILE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "identity file is too large",
+        ));
+    }
+    Ok(input)
+}
+
+// Read an identity file, prompting for the passphrase of protected identities
+fn read_identity(path: &str) -> Zeroizing<Vec<u8>> {
+    let identity_file = exit_on_error(read_secret_input(path), "cannot read identity file");
+    let identity_file = exit_on_error(std::str::from_utf8(&identity_file), "invalid identity file");
+
+    let identity = exit_on_error(identity_from_string(identity_file), "invalid identity");
+    if is_encrypted_identity(&identity) {
+        exit_on_error(
+            decrypt_identity(&identity, &read_passphrase()),
+            "cannot unlock identity",
+        )
+    } else {
+        identity
+    }
+}
+
+// Write to a file, or standard output
+fn write_output(path: Option<PathBuf>, data: &[u8]) -> io::Result<()> {
+    match path {
+        Some(path) => fs::write(path, data),
+        None => io::stdout().write_all(data),
+    }
+}
+
+pub fn to_recipient(identity_path: &str) {
+    let identity = read_identity(identity_path);
+    let recipient = exit_on_error(
+        convert_identity_to_recipient(&identity).and_then(|r| recipient_to_string(PLUGIN_NAME, &r)),
+        "invalid identity",
+    );
+    println!("{recipient}");
+}
+
+pub fn seal(recipient: &str, args: HpkeArgs) {
+    let recipient = exit_on_error(
+        recipient_from_string(recipient).and_then(|r| resolve_recipient(&r)),
+        "invalid recipient",
+    );
+    let plaintext = exit_on_error(read_input(&args.input), "cannot read input");
+    let sealed = exit_on_error(
+        age_plugin_hpke::seal(
+            &recipient,
+            args.info.as_bytes(),
+            args.aad.as_bytes(),
+            &plaintext,
+        ),
+        "cannot seal",
+    );
+    let output = if args.base64 {
+        format!("{}\n", BASE64.encode(sealed)).into_bytes()
+    } else {
+        sealed
+    };
+    exit_on_error(write_output(args.output, &output), "cannot write output");
+}
+
+pub fn open(identity_path: &str, args: HpkeArgs) {
+    let identity = read_identity(identity_path);
+    let input = exit_on_error(read_input(&args.input), "cannot read input");
+    let sealed = if args.base64 {
+        let input = exit_on_error(String::from_utf8(input), "invalid base64 input");
+        exit_on_error(BASE64.decode(input.trim()), "invalid base64 input")
+    } else {
+        input
+    };
+    let plaintext = exit_on_error(
+        age_plugin_hpke::open(
+            &identity,
+            args.info.as_bytes(),
+            args.aad.as_bytes(),
+            &sealed,
+        ),
+        "cannot open",
+    );
+    exit_on_error(write_output(args.output, &plaintext), "cannot write output");
+}
+
+pub fn compact(recipient: &str) {
+    let recipient = exit_on_error(
+        recipient_from_string(recipient)
+            .and_then(|r| recipient_to_string(PLUGIN_NAME, &compact_recipient(&r))),
+        "invalid recipient",
+    );
+    println!("{recipient}");
+}
+
+pub fn inspect(path: &str, json: bool) {
+    let input = exit_on_error(read_input(path), "cannot read input");
+    let inspections = exit_on_error(
+        age_plugin_hpke::inspect::inspect(&input),
+        "cannot inspect input",
+    );
+    if json {
+        println!("{}", serde_json::to_string_pretty(&inspections).unwrap());
+    } else {
+        let inspections: Vec<String> = inspections.iter().map(ToString::to_string).collect();
+        println!("{}", inspections.join("\n\n"));
+    }
+}
+
+pub fn suites() {
+    for (aead, kdf, kem) in supported_suites() {
+        println!("{} {} {}", kem.name(), kdf.name(), aead.name());
+    }
 }
 
 pub fn run_state_machine(state_machine: String) {
@@ -24,13 +250,14 @@ fn main() {
         return run_state_machine(state_machine);
     }
 
-    if let Some(args) = cli.generate {
-        if args.generate {
-            generate(
-                &args.associated_data.unwrap(),
-                args.kem.unwrap(),
-                args.aead.unwrap(),
-            )
-        }
+    match cli.command {
+        Some(Command::Generate(args)) => generate(args),
+        Some(Command::Recipient { identity }) => to_recipient(&identity),
+        Some(Command::Compact { recipient }) => compact(&recipient),
+        Some(Command::Inspect { file, json }) => inspect(&file, json),
+        Some(Command::Suites) => suites(),
+        Some(Command::Seal { recipient, args }) => seal(&recipient, args),
+        Some(Command::Open { identity, args }) => open(&identity, args),
+        None => (),
     }
 }
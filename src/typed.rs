@@ -0,0 +1,334 @@
+//! Recipients and identities of a suite known at compile time.
+//!
+//! [`HpkeRecipient`] and [`HpkeIdentity`] use the hpke_pq types directly, instead of the runtime
+//! dispatch and checks of the agile module. They produce and accept the same stanzas as
+//! [`Recipient`] and [`Identity`], and convert to and from them.
+
+use std::marker::PhantomData;
+
+use age_core::format::{FileKey, Stanza};
+use hpke_pq::{
+    aead::Aead, kdf::Kdf as KdfTrait, kem::Kem as KemTrait, setup_receiver, setup_sender,
+    Deserializable, OpModeR, OpModeS, Serializable,
+};
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
+use zeroize::Zeroizing;
+
+use crate::{
+    agile::{AeadAlg, AgileHpkeError, AgilePrivateKey, AgilePublicKey, KdfAlg, KemAlg},
+    backend::is_export_only,
+    internal::{encrypt_error, parse_stanza, unwrap_with_context, wrap_with_context, INFO_STR},
+    Identity, Recipient,
+};
+
+// Checks an agile suite is the suite of the hpke_pq types
+fn check_suite<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(
+    aead: &AeadAlg,
+    kdf: &KdfAlg,
+    kem: &KemAlg,
+) -> Result<(), AgileHpkeError> {
+    if aead.to_u16() != A::AEAD_ID {
+        let expected = AeadAlg::try_from_u16(A::AEAD_ID)?;
+        return Err(AgileHpkeError::AlgMismatch(
+            (aead.name(), "aead"),
+            (expected.name(), "A::AEAD_ID"),
+        ));
+    }
+    if kdf.to_u16() != Kdf::KDF_ID {
+        let expected = KdfAlg::try_from_u16(Kdf::KDF_ID)?;
+        return Err(AgileHpkeError::AlgMismatch(
+            (kdf.name(), "kdf"),
+            (expected.name(), "Kdf::KDF_ID"),
+        ));
+    }
+    if kem.to_u16() != Kem::KEM_ID {
+        let expected = KemAlg::try_from_u16(Kem::KEM_ID)?;
+        return Err(AgileHpkeError::AlgMismatch(
+            (kem.name(), "kem"),
+            (expected.name(), "Kem::KEM_ID"),
+        ));
+    }
+    Ok(())
+}
+
+/// HPKE recipient of the suite `A`, `Kdf`, `Kem`, usable with `age::Encryptor`.
+pub struct HpkeRecipient<A, Kdf, Kem: KemTrait> {
+    public_key: Kem::PublicKey,
+    associated_data: Vec<u8>,
+    suite: PhantomData<fn() -> (A, Kdf)>,
+}
+
+impl<A, Kdf, Kem: KemTrait> Clone for HpkeRecipient<A, Kdf, Kem> {
+    fn clone(&self) -> Self {
+        Self {
+            public_key: self.public_key.clone(),
+            associated_data: self.associated_data.clone(),
+            suite: PhantomData,
+        }
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> HpkeRecipient<A, Kdf, Kem> {
+    pub fn new(public_key: Kem::PublicKey, associated_data: &[u8]) -> Self {
+        Self {
+            public_key,
+            associated_data: associated_data.to_vec(),
+            suite: PhantomData,
+        }
+    }
+
+    pub fn public_key(&self) -> &Kem::PublicKey {
+        &self.public_key
+    }
+
+    pub fn associated_data(&self) -> &[u8] {
+        &self.associated_data
+    }
+
+    /// [`age::Recipient::wrap_file_key`] with the ephemeral key generated from `csprng`.
+    pub fn wrap_file_key_with_rng<R: CryptoRng + RngCore>(
+        &self,
+        file_key: &FileKey,
+        csprng: &mut R,
+    ) -> Result<Vec<Stanza>, age::EncryptError> {
+        let (encapped_key, mut sender_ctx) =
+            setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &self.public_key, INFO_STR, csprng)
+                .map_err(|e| encrypt_error(e.into()))?;
+
+        let stanza = wrap_with_context(
+            &mut sender_ctx,
+            is_export_only(A::AEAD_ID),
+            &self.associated_data,
+            encapped_key.to_bytes().to_vec(),
+            file_key,
+        )?;
+        Ok(vec![stanza])
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> age::Recipient for HpkeRecipient<A, Kdf, Kem> {
+    fn wrap_file_key(&self, file_key: &FileKey) -> Result<Vec<Stanza>, age::EncryptError> {
+        self.wrap_file_key_with_rng(file_key, &mut StdRng::from_entropy())
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> TryFrom<&Recipient> for HpkeRecipient<A, Kdf, Kem> {
+    type Error = AgileHpkeError;
+
+    fn try_from(recipient: &Recipient) -> Result<Self, Self::Error> {
+        check_suite::<A, Kdf, Kem>(recipient.aead(), recipient.kdf(), recipient.kem())?;
+        let public_key = Kem::PublicKey::from_bytes(&recipient.public_key().to_bytes())?;
+        Ok(Self::new(public_key, recipient.associated_data()))
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> TryFrom<&HpkeRecipient<A, Kdf, Kem>> for Recipient {
+    type Error = AgileHpkeError;
+
+    fn try_from(recipient: &HpkeRecipient<A, Kdf, Kem>) -> Result<Self, Self::Error> {
+        let kem = KemAlg::try_from_u16(Kem::KEM_ID)?;
+        Ok(Recipient::new(
+            kem.clone(),
+            AeadAlg::try_from_u16(A::AEAD_ID)?,
+            KdfAlg::try_from_u16(Kdf::KDF_ID)?,
+            &AgilePublicKey::new(kem, &recipient.public_key.to_bytes()),
+            &recipient.associated_data,
+        ))
+    }
+}
+
+/// HPKE identity of the suite `A`, `Kdf`, `Kem`, usable with `age::Decryptor`.
+pub struct HpkeIdentity<A, Kdf, Kem: KemTrait> {
+    private_key: Kem::PrivateKey,
+    associated_data: Vec<u8>,
+    suite: PhantomData<fn() -> (A, Kdf)>,
+}
+
+impl<A, Kdf, Kem: KemTrait> Clone for HpkeIdentity<A, Kdf, Kem> {
+    fn clone(&self) -> Self {
+        Self {
+            private_key: self.private_key.clone(),
+            associated_data: self.associated_data.clone(),
+            suite: PhantomData,
+        }
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> HpkeIdentity<A, Kdf, Kem> {
+    pub fn new(private_key: Kem::PrivateKey, associated_data: &[u8]) -> Self {
+        Self {
+            private_key,
+            associated_data: associated_data.to_vec(),
+            suite: PhantomData,
+        }
+    }
+
+    pub fn private_key(&self) -> &Kem::PrivateKey {
+        &self.private_key
+    }
+
+    pub fn associated_data(&self) -> &[u8] {
+        &self.associated_data
+    }
+
+    pub fn to_recipient(&self) -> HpkeRecipient<A, Kdf, Kem> {
+        HpkeRecipient::new(Kem::sk_to_pk(&self.private_key), &self.associated_data)
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> age::Identity for HpkeIdentity<A, Kdf, Kem> {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, age::DecryptError>> {
+        let stanza = match parse_stanza(stanza, is_export_only(A::AEAD_ID))? {
+            Ok(stanza) => stanza,
+            Err(e) => return Some(Err(e)),
+        };
+
+        // Stanzas wrapped to other recipients fail to open, and are skipped
+        let encapped_key = Kem::EncappedKey::from_bytes(stanza.encapped_key()).ok()?;
+        let mut receiver_ctx = setup_receiver::<A, Kdf, Kem>(
+            &OpModeR::Base,
+            &self.private_key,
+            &encapped_key,
+            INFO_STR,
+        )
+        .ok()?;
+
+        unwrap_with_context(&mut receiver_ctx, stanza).map(Ok)
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> TryFrom<&Identity> for HpkeIdentity<A, Kdf, Kem> {
+    type Error = AgileHpkeError;
+
+    fn try_from(identity: &Identity) -> Result<Self, Self::Error> {
+        check_suite::<A, Kdf, Kem>(identity.aead(), identity.kdf(), identity.kem())?;
+        let private_key = Kem::PrivateKey::from_bytes(&identity.private_key().to_bytes())?;
+        Ok(Self::new(private_key, identity.associated_data()))
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> TryFrom<&HpkeIdentity<A, Kdf, Kem>> for Identity {
+    type Error = AgileHpkeError;
+
+    fn try_from(identity: &HpkeIdentity<A, Kdf, Kem>) -> Result<Self, Self::Error> {
+        let kem = KemAlg::try_from_u16(Kem::KEM_ID)?;
+        let private_key_bytes = Zeroizing::new(identity.private_key.to_bytes());
+        Ok(Identity::new(
+            kem.clone(),
+            AeadAlg::try_from_u16(A::AEAD_ID)?,
+            KdfAlg::try_from_u16(Kdf::KDF_ID)?,
+            &AgilePrivateKey::new(kem, &private_key_bytes),
+            &identity.associated_data,
+        ))
+    }
+}
+
+#[cfg(all(
+    test,
+    any(
+        all(feature = "x25519", feature = "chacha20poly1305"),
+        all(feature = "x25519", feature = "aes-gcm"),
+        all(feature = "pq-hybrid", feature = "export-only"),
+        all(feature = "nist-p256", feature = "aes-gcm")
+    )
+))]
+mod tests {
+    use super::*;
+    use crate::agile::agile_gen_keypair;
+    use age::{Identity as _, Recipient as _};
+    use age_core::secrecy::ExposeSecret;
+
+    #[cfg(any(
+        all(feature = "x25519", feature = "chacha20poly1305"),
+        all(feature = "pq-hybrid", feature = "export-only"),
+        all(feature = "nist-p256", feature = "aes-gcm")
+    ))]
+    fn interop<A: Aead, Kdf: KdfTrait, Kem: KemTrait>() {
+        let file_key = FileKey::from([7; 16]);
+        let (kem, aead, kdf) = (
+            KemAlg::try_from_u16(Kem::KEM_ID).unwrap(),
+            AeadAlg::try_from_u16(A::AEAD_ID).unwrap(),
+            KdfAlg::try_from_u16(Kdf::KDF_ID).unwrap(),
+        );
+        let keypair = agile_gen_keypair(kem.clone(), &mut StdRng::from_entropy()).unwrap();
+        let identity = Identity::new(kem, aead, kdf, keypair.private_key(), b"ad");
+        let recipient = Recipient::try_from(identity.clone()).unwrap();
+
+        let typed_identity = HpkeIdentity::<A, Kdf, Kem>::try_from(&identity).unwrap();
+        let typed_recipient = HpkeRecipient::<A, Kdf, Kem>::try_from(&recipient).unwrap();
+        assert!(typed_identity.to_recipient().public_key() == typed_recipient.public_key());
+
+        // Typed wrap, agile unwrap
+        let stanzas = typed_recipient.wrap_file_key(&file_key).unwrap();
+        let unwrapped = identity.unwrap_stanza(&stanzas[0]).unwrap().unwrap();
+        assert_eq!(unwrapped.expose_secret(), file_key.expose_secret());
+
+        // Agile wrap, typed unwrap
+        let stanzas = recipient.wrap_file_key(&file_key).unwrap();
+        let unwrapped = typed_identity.unwrap_stanza(&stanzas[0]).unwrap().unwrap();
+        assert_eq!(unwrapped.expose_secret(), file_key.expose_secret());
+
+        // Conversions back to the agile types are lossless
+        assert_eq!(Identity::try_from(&typed_identity).unwrap(), identity);
+        assert_eq!(Recipient::try_from(&typed_recipient).unwrap(), recipient);
+    }
+
+    #[cfg(all(feature = "x25519", feature = "chacha20poly1305"))]
+    #[test]
+    fn x25519_interoperates() {
+        use hpke_pq::{aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::X25519HkdfSha256};
+        interop::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256>();
+    }
+
+    #[cfg(all(feature = "pq-hybrid", feature = "export-only"))]
+    #[test]
+    fn pq_hybrid_interoperates() {
+        use hpke_pq::{aead::ExportOnlyAead, kdf::HkdfSha384, kem::X25519Kyber768Draft00};
+        interop::<ExportOnlyAead, HkdfSha384, X25519Kyber768Draft00>();
+    }
+
+    #[cfg(all(feature = "nist-p256", feature = "aes-gcm"))]
+    #[test]
+    fn p256_interoperates() {
+        use hpke_pq::{aead::AesGcm128, kdf::HkdfSha256, kem::DhP256HkdfSha256};
+        interop::<AesGcm128, HkdfSha256, DhP256HkdfSha256>();
+    }
+
+    #[cfg(all(feature = "x25519", feature = "aes-gcm"))]
+    #[test]
+    fn suite_mismatch() {
+        use hpke_pq::{
+            aead::{AesGcm128, AesGcm256},
+            kdf::{HkdfSha256, HkdfSha384, HkdfSha512},
+            kem::X25519HkdfSha256,
+        };
+
+        let kem = KemAlg::X25519HkdfSha256;
+        let keypair = agile_gen_keypair(kem.clone(), &mut StdRng::from_entropy()).unwrap();
+        let identity = Identity::new(
+            kem,
+            AeadAlg::AesGcm128,
+            KdfAlg::HkdfSha256,
+            keypair.private_key(),
+            b"",
+        );
+        let recipient = Recipient::try_from(identity.clone()).unwrap();
+
+        let e = HpkeIdentity::<AesGcm256, HkdfSha256, X25519HkdfSha256>::try_from(&identity)
+            .err()
+            .unwrap();
+        assert!(matches!(e, AgileHpkeError::AlgMismatch(..)));
+        let e = HpkeRecipient::<AesGcm128, HkdfSha512, X25519HkdfSha256>::try_from(&recipient)
+            .err()
+            .unwrap();
+        assert!(matches!(e, AgileHpkeError::AlgMismatch(..)));
+
+        // Stanzas of another suite are skipped
+        let typed = HpkeIdentity::<AesGcm128, HkdfSha384, X25519HkdfSha256>::new(
+            hpke_pq::Deserializable::from_bytes(&keypair.private_key().to_bytes()).unwrap(),
+            b"",
+        );
+        let stanzas = recipient.wrap_file_key(&FileKey::from([7; 16])).unwrap();
+        assert!(typed.unwrap_stanza(&stanzas[0]).is_none());
+    }
+}
@@ -0,0 +1,378 @@
+//! End-to-end tests with the age library as the client.
+//!
+//! Files are encrypted and decrypted with `age::Encryptor` and `age::Decryptor`, either with the
+//! plugin `Recipient` and `Identity` directly, or through the age plugin protocol with the compiled
+//! binary.
+
+use std::{
+    env,
+    io::{Read, Write},
+    iter,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex, Once},
+};
+
+use age::{secrecy::SecretString, x25519};
+use age_plugin_hpke::{
+    agile::supported_suites, encrypt_identity, identity_to_string, new_identity,
+    recipient_to_string, Identity, Recipient, Zeroizing,
+};
+
+const PLUGIN_NAME: &str = "hpke";
+const PLAINTEXT: &[u8] = b"Hello age-plugin-hpke!";
+const PASSPHRASE: &str = "correct horse battery staple";
+
+// Identity and recipient bytes for every supported KEM and AEAD, with the KEM's KDF
+fn every_suite() -> Vec<(Zeroizing<Vec<u8>>, Vec<u8>)> {
+    supported_suites()
+        .into_iter()
+        .filter(|(_, kdf, kem)| *kdf == kem.kdf_alg())
+        .map(|(aead, kdf, kem)| new_identity(kem, aead, kdf, "user@example.com").unwrap())
+        .collect()
+}
+
+fn encrypt(recipients: Vec<Box<dyn age::Recipient + Send>>) -> Vec<u8> {
+    let mut encrypted = vec![];
+    let encryptor = age::Encryptor::with_recipients(recipients).unwrap();
+    let mut writer = encryptor.wrap_output(&mut encrypted).unwrap();
+    writer.write_all(PLAINTEXT).unwrap();
+    writer.finish().unwrap();
+    encrypted
+}
+
+fn decrypt(identity: &dyn age::Identity, encrypted: &[u8]) -> Result<Vec<u8>, age::DecryptError> {
+    let age::Decryptor::Recipients(decryptor) = age::Decryptor::new(encrypted)? else {
+        panic!("expected a file encrypted to recipients");
+    };
+    let mut reader = decryptor.decrypt(iter::once(identity))?;
+    let mut decrypted = vec![];
+    reader.read_to_end(&mut decrypted)?;
+    Ok(decrypted)
+}
+
+// Answers every plugin request, as a non-interactive age client would
+#[derive(Clone)]
+struct TestCallbacks;
+
+impl age::Callbacks for TestCallbacks {
+    fn display_message(&self, _message: &str) {}
+
+    fn confirm(&self, _message: &str, _yes_string: &str, _no_string: Option<&str>) -> Option<bool> {
+        Some(true)
+    }
+
+    fn request_public_string(&self, _description: &str) -> Option<String> {
+        None
+    }
+
+    fn request_passphrase(&self, _description: &str) -> Option<SecretString> {
+        None
+    }
+}
+
+// Answers passphrase requests with the same passphrase, and records the prompts, and the
+// messages and confirmations shown to the user
+#[derive(Clone, Default)]
+struct PassphraseCallbacks {
+    prompts: Arc<Mutex<Vec<String>>>,
+    messages: Arc<Mutex<Vec<String>>>,
+}
+
+impl age::Callbacks for PassphraseCallbacks {
+    fn display_message(&self, message: &str) {
+        self.messages.lock().unwrap().push(message.to_owned());
+    }
+
+    fn confirm(&self, message: &str, _yes_string: &str, _no_string: Option<&str>) -> Option<bool> {
+        self.messages.lock().unwrap().push(message.to_owned());
+        Some(true)
+    }
+
+    fn request_public_string(&self, _description: &str) -> Option<String> {
+        None
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<SecretString> {
+        self.prompts.lock().unwrap().push(description.to_owned());
+        Some(SecretString::new(PASSPHRASE.to_owned()))
+    }
+}
+
+// age finds plugins in PATH, add the directory of the compiled binary to it
+fn add_plugin_to_path() {
+    static ONCE: Once = Once::new();
+    ONCE.call_once(|| {
+        let binary = Path::new(env!("CARGO_BIN_EXE_age-plugin-hpke"));
+        let mut paths = vec![binary.parent().unwrap().to_path_buf()];
+        paths.extend(env::split_paths(&env::var_os("PATH").unwrap_or_default()));
+        env::set_var("PATH", env::join_paths(paths).unwrap());
+    });
+}
+
+fn plugin_recipient(recipient: &[u8]) -> Box<dyn age::Recipient + Send> {
+    add_plugin_to_path();
+    let recipient =
+        age::plugin::Recipient::from_str(&recipient_to_string(PLUGIN_NAME, recipient).unwrap())
+            .unwrap();
+    Box::new(
+        age::plugin::RecipientPluginV1::new(PLUGIN_NAME, &[recipient], &[], TestCallbacks).unwrap(),
+    )
+}
+
+fn plugin_identity(identity: &[u8]) -> age::plugin::IdentityPluginV1<TestCallbacks> {
+    add_plugin_to_path();
+    let identity =
+        age::plugin::Identity::from_str(&identity_to_string(PLUGIN_NAME, identity).unwrap())
+            .unwrap();
+    age::plugin::IdentityPluginV1::new(PLUGIN_NAME, &[identity], TestCallbacks).unwrap()
+}
+
+#[test]
+fn library_every_suite() {
+    for (identity, recipient) in every_suite() {
+        let identity = Identity::from_bytes(&identity).unwrap();
+        let recipient = Recipient::from_bytes(&recipient).unwrap();
+
+        let encrypted = encrypt(vec![Box::new(recipient)]);
+        assert_eq!(decrypt(&identity, &encrypted).unwrap(), PLAINTEXT);
+    }
+}
+
+#[test]
+fn library_multiple_recipients() {
+    let keys = every_suite();
+    let native_identity = x25519::Identity::generate();
+
+    let mut recipients: Vec<Box<dyn age::Recipient + Send>> = keys
+        .iter()
+        .map(|(_, recipient)| {
+            Box::new(Recipient::from_bytes(recipient).unwrap()) as Box<dyn age::Recipient + Send>
+        })
+        .collect();
+    recipients.push(Box::new(native_identity.to_public()));
+    let encrypted = encrypt(recipients);
+
+    for (identity, _) in &keys {
+        let identity = Identity::from_bytes(identity).unwrap();
+        assert_eq!(decrypt(&identity, &encrypted).unwrap(), PLAINTEXT);
+    }
+    assert_eq!(decrypt(&native_identity, &encrypted).unwrap(), PLAINTEXT);
+
+    // Identities without a stanza in the file do not decrypt it
+    let (aead, kdf, kem) = supported_suites().remove(0);
+    let (other_identity, _) = new_identity(kem, aead, kdf, "user@example.com").unwrap();
+    let other_identity = Identity::from_bytes(&other_identity).unwrap();
+    assert!(matches!(
+        decrypt(&other_identity, &encrypted),
+        Err(age::DecryptError::NoMatchingKeys)
+    ));
+}
+
+#[test]
+fn plugin_every_suite() {
+    for (identity, recipient) in every_suite() {
+        let encrypted = encrypt(vec![plugin_recipient(&recipient)]);
+        assert_eq!(
+            decrypt(&plugin_identity(&identity), &encrypted).unwrap(),
+            PLAINTEXT
+        );
+
+        // The plugin and the library produce files the other decrypts
+        assert_eq!(
+            decrypt(&Identity::from_bytes(&identity).unwrap(), &encrypted).unwrap(),
+            PLAINTEXT
+        );
+        let encrypted = encrypt(vec![Box::new(Recipient::from_bytes(&recipient).unwrap())]);
+        assert_eq!(
+            decrypt(&plugin_identity(&identity), &encrypted).unwrap(),
+            PLAINTEXT
+        );
+    }
+}
+
+#[test]
+fn plugin_multiple_recipients() {
+    let keys = every_suite();
+    let native_identity = x25519::Identity::generate();
+
+    // A single plugin invocation wraps the file key to every recipient
+    add_plugin_to_path();
+    let plugin_recipients: Vec<age::plugin::Recipient> = keys
+        .iter()
+        .map(|(_, recipient)| {
+            recipient_to_string(PLUGIN_NAME, recipient)
+                .unwrap()
+                .parse()
+                .unwrap()
+        })
+        .collect();
+    let plugin =
+        age::plugin::RecipientPluginV1::new(PLUGIN_NAME, &plugin_recipients, &[], TestCallbacks)
+            .unwrap();
+    let encrypted = encrypt(vec![
+        Box::new(plugin),
+        Box::new(native_identity.to_public()),
+    ]);
+
+    for (identity, _) in &keys {
+        assert_eq!(
+            decrypt(&plugin_identity(identity), &encrypted).unwrap(),
+            PLAINTEXT
+        );
+    }
+    assert_eq!(decrypt(&native_identity, &encrypted).unwrap(), PLAINTEXT);
+}
+
+#[test]
+fn plugin_encrypts_to_identities() {
+    let (aead, kdf, kem) = supported_suites().remove(0);
+    let (identity, recipient) = new_identity(kem, aead, kdf, "user@example.com").unwrap();
+
+    // age -e -i passes the identity to the recipient plugin, which wraps to its recipient
+    add_plugin_to_path();
+    let plugin_identity_arg =
+        age::plugin::Identity::from_str(&identity_to_string(PLUGIN_NAME, &identity).unwrap())
+            .unwrap();
+    let plugin = age::plugin::RecipientPluginV1::new(
+        PLUGIN_NAME,
+        &[],
+        &[plugin_identity_arg],
+        TestCallbacks,
+    )
+    .unwrap();
+    let encrypted = encrypt(vec![Box::new(plugin)]);
+
+    assert_eq!(
+        decrypt(&plugin_identity(&identity), &encrypted).unwrap(),
+        PLAINTEXT
+    );
+    let identity = Identity::from_bytes(&identity).unwrap();
+    assert_eq!(decrypt(&identity, &encrypted).unwrap(), PLAINTEXT);
+    assert_eq!(
+        Recipient::try_from(identity).unwrap().to_bytes(),
+        Recipient::from_bytes(&recipient).unwrap().to_bytes()
+    );
+}
+
+#[test]
+fn plugin_unlocks_identities_when_needed() {
+    let (aead, kdf, kem) = supported_suites().remove(0);
+    let (identity, recipient) = new_identity(kem, aead, kdf, "user@example.com").unwrap();
+    let encrypted_identity = encrypt_identity(&identity, SecretString::new(PASSPHRASE.to_owned()));
+
+    add_plugin_to_path();
+    let plugin_identity = |callbacks: PassphraseCallbacks| {
+        let identity = age::plugin::Identity::from_str(
+            &identity_to_string(PLUGIN_NAME, &encrypted_identity).unwrap(),
+        )
+        .unwrap();
+        age::plugin::IdentityPluginV1::new(PLUGIN_NAME, &[identity], callbacks).unwrap()
+    };
+
+    // No passphrase is asked for files without an hpke stanza
+    let callbacks = PassphraseCallbacks::default();
+    let native_identity = x25519::Identity::generate();
+    let encrypted = encrypt(vec![Box::new(native_identity.to_public())]);
+    assert!(decrypt(&plugin_identity(callbacks.clone()), &encrypted).is_err());
+    assert!(callbacks.prompts.lock().unwrap().is_empty());
+
+    let encrypted = encrypt(vec![Box::new(Recipient::from_bytes(&recipient).unwrap())]);
+    assert_eq!(
+        decrypt(&plugin_identity(callbacks.clone()), &encrypted).unwrap(),
+        PLAINTEXT
+    );
+    assert_eq!(
+        *callbacks.prompts.lock().unwrap(),
+        ["Enter passphrase for HPKE identity 0"]
+    );
+}
+
+#[test]
+fn plugin_encrypts_to_encrypted_identities() {
+    let (aead, kdf, kem) = supported_suites().remove(0);
+    let (identity, _) = new_identity(kem, aead, kdf, "user@example.com").unwrap();
+    let encrypted_identity = encrypt_identity(&identity, SecretString::new(PASSPHRASE.to_owned()));
+
+    // age -e -i with a passphrase protected identity unlocks it to wrap to its recipient
+    add_plugin_to_path();
+    let plugin_identity_arg = age::plugin::Identity::from_str(
+        &identity_to_string(PLUGIN_NAME, &encrypted_identity).unwrap(),
+    )
+    .unwrap();
+    let callbacks = PassphraseCallbacks::default();
+    let plugin = age::plugin::RecipientPluginV1::new(
+        PLUGIN_NAME,
+        &[],
+        &[plugin_identity_arg],
+        callbacks.clone(),
+    )
+    .unwrap();
+    let encrypted = encrypt(vec![Box::new(plugin)]);
+    assert_eq!(
+        *callbacks.prompts.lock().unwrap(),
+        ["Enter passphrase for HPKE identity 0"]
+    );
+
+    let identity = Identity::from_bytes(&identity).unwrap();
+    assert_eq!(decrypt(&identity, &encrypted).unwrap(), PLAINTEXT);
+}
+
+#[cfg(all(feature = "pq-hybrid", feature = "aes-gcm"))]
+#[test]
+fn plugin_warns_once_about_deprecated_suites() {
+    use age_plugin_hpke::agile::{AeadAlg, KdfAlg, KemAlg};
+
+    let keys: Vec<_> = (0..2)
+        .map(|_| {
+            new_identity(
+                KemAlg::X25519Kyber768Draft00,
+                AeadAlg::AesGcm128,
+                KdfAlg::HkdfSha256,
+                "user@example.com",
+            )
+            .unwrap()
+        })
+        .collect();
+
+    // Encrypting to several deprecated recipients asks for confirmation once
+    add_plugin_to_path();
+    let plugin_recipients: Vec<age::plugin::Recipient> = keys
+        .iter()
+        .map(|(_, recipient)| {
+            recipient_to_string(PLUGIN_NAME, recipient)
+                .unwrap()
+                .parse()
+                .unwrap()
+        })
+        .collect();
+    let callbacks = PassphraseCallbacks::default();
+    let plugin = age::plugin::RecipientPluginV1::new(
+        PLUGIN_NAME,
+        &plugin_recipients,
+        &[],
+        callbacks.clone(),
+    )
+    .unwrap();
+    let encrypted = encrypt(vec![Box::new(plugin)]);
+    assert_eq!(callbacks.messages.lock().unwrap().len(), 1);
+
+    // Identities only warn about files they may decrypt
+    let plugin_identity = |callbacks: PassphraseCallbacks| {
+        let identity =
+            age::plugin::Identity::from_str(&identity_to_string(PLUGIN_NAME, &keys[0].0).unwrap())
+                .unwrap();
+        age::plugin::IdentityPluginV1::new(PLUGIN_NAME, &[identity], callbacks).unwrap()
+    };
+    let callbacks = PassphraseCallbacks::default();
+    let native_identity = x25519::Identity::generate();
+    let native_encrypted = encrypt(vec![Box::new(native_identity.to_public())]);
+    assert!(decrypt(&plugin_identity(callbacks.clone()), &native_encrypted).is_err());
+    assert!(callbacks.messages.lock().unwrap().is_empty());
+
+    assert_eq!(
+        decrypt(&plugin_identity(callbacks.clone()), &encrypted).unwrap(),
+        PLAINTEXT
+    );
+    assert_eq!(callbacks.messages.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,80 @@
+//! Golden outputs of key generation and file key wrapping, with a seeded RNG.
+//!
+//! These values only change with the encoding of keys and stanzas, or with how the randomness is
+//! drawn.
+
+#![cfg(all(feature = "x25519", feature = "chacha20poly1305"))]
+
+use age::Identity as _;
+use age_core::format::FileKey;
+use age_core::secrecy::ExposeSecret;
+use age_plugin_hpke::{
+    agile::{AeadAlg, KdfAlg, KemAlg},
+    identity_to_string, new_identity_with_rng, recipient_to_string,
+    typed::HpkeRecipient,
+    Identity, Recipient,
+};
+use base64::{engine::general_purpose::STANDARD_NO_PAD as BASE64, Engine};
+use hpke_pq::{aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::X25519HkdfSha256};
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+const IDENTITY: &str = "AGE-PLUGIN-HPKE-1QQPQQQPQXQKY4YZRHGZ7Q3ZKC8524J733RT8Q60GDE9GVX52FSK98MZXK3PSVEM0D3JX2MSN7AFHY";
+const RECIPIENT: &str =
+    "age1hpke1qqpqqqpqjzu6da0mqjdj0zy96jqerr2f77n33qadvvrvr838c9xw8dlcha8qvem0d3jx2ms4d907w";
+const STANZA_ARGS: [&str; 3] = [
+    "Z29sZGVu",
+    "bTd5lQtC5UfvPohUWL4BQJFq2fRfugxCFlXkTCI8NCw",
+    "HQpjwrP3jgg8Gh3ycfWsOw",
+];
+const STANZA_BODY: &str = "t/A6VrTQjPOWe1c2AT6KrA";
+
+fn new_identity() -> (Vec<u8>, Vec<u8>) {
+    let (identity, recipient) = new_identity_with_rng(
+        KemAlg::X25519HkdfSha256,
+        AeadAlg::ChaCha20Poly1305,
+        KdfAlg::HkdfSha256,
+        "golden",
+        &mut ChaCha20Rng::seed_from_u64(1),
+    )
+    .unwrap();
+    (identity.to_vec(), recipient)
+}
+
+#[test]
+fn identity_is_golden() {
+    let (identity, recipient) = new_identity();
+    assert_eq!(*identity_to_string("hpke", &identity).unwrap(), IDENTITY);
+    assert_eq!(recipient_to_string("hpke", &recipient).unwrap(), RECIPIENT);
+}
+
+#[test]
+fn stanza_is_golden() {
+    let (identity, recipient) = new_identity();
+    let file_key = FileKey::from([7; 16]);
+    let recipient = Recipient::from_bytes(&recipient).unwrap();
+    let stanzas = recipient
+        .wrap_file_key_with_rng(&file_key, &mut ChaCha20Rng::seed_from_u64(2))
+        .unwrap();
+    let stanza = &stanzas[0];
+    assert_eq!(stanza.tag, "hpke");
+    assert_eq!(stanza.args, STANZA_ARGS);
+    assert_eq!(BASE64.encode(&stanza.body), STANZA_BODY);
+
+    let unwrapped = Identity::from_bytes(&identity)
+        .unwrap()
+        .unwrap_stanza(stanza)
+        .unwrap()
+        .unwrap();
+    assert_eq!(unwrapped.expose_secret(), file_key.expose_secret());
+
+    // The typed recipient draws the same randomness
+    let typed =
+        HpkeRecipient::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256>::try_from(&recipient)
+            .unwrap();
+    let typed_stanzas = typed
+        .wrap_file_key_with_rng(&file_key, &mut ChaCha20Rng::seed_from_u64(2))
+        .unwrap();
+    assert_eq!(typed_stanzas[0].args, STANZA_ARGS);
+    assert_eq!(BASE64.encode(&typed_stanzas[0].body), STANZA_BODY);
+}
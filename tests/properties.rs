@@ -0,0 +1,112 @@
+//! Property tests of key encoding and parsing.
+//!
+//! Keys round-trip through their byte and bech32 encodings, and parsing arbitrary input returns
+//! an error instead of panicking.
+
+use age::Identity as _;
+use age_core::format::Stanza;
+use age_plugin_hpke::{
+    agile::{agile_derive_keypair, supported_suites, AeadAlg, KdfAlg, KemAlg},
+    encoding, identity_from_string, identity_to_string, recipient_from_string, recipient_to_string,
+    Identity, Recipient,
+};
+use proptest::prelude::*;
+
+const PLUGIN_NAME: &str = "hpke";
+
+// Suites enabled by the crate features
+fn suite() -> impl Strategy<Value = (AeadAlg, KdfAlg, KemAlg)> {
+    prop::sample::select(supported_suites())
+}
+
+fn identity() -> impl Strategy<Value = Identity> {
+    (
+        suite(),
+        any::<[u8; 32]>(),
+        prop::collection::vec(any::<u8>(), 0..256),
+    )
+        .prop_map(|((aead, kdf, kem), ikm, associated_data)| {
+            let keypair = agile_derive_keypair(kem.clone(), &ikm).unwrap();
+            Identity::new(kem, aead, kdf, keypair.private_key(), &associated_data)
+        })
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn identity_bytes_roundtrip(identity in identity()) {
+        let bytes = identity.to_bytes();
+        prop_assert_eq!(Identity::from_bytes(&bytes).unwrap(), identity);
+    }
+
+    #[test]
+    fn recipient_bytes_roundtrip(identity in identity()) {
+        let recipient = Recipient::try_from(identity).unwrap();
+        let bytes = recipient.to_bytes();
+        prop_assert_eq!(Recipient::from_bytes(&bytes).unwrap(), recipient);
+    }
+
+    #[test]
+    fn identity_string_roundtrip(identity in identity()) {
+        let bytes = identity.to_bytes();
+        let encoded = identity_to_string(PLUGIN_NAME, &bytes).unwrap();
+        prop_assert_eq!(identity_from_string(&encoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn recipient_string_roundtrip(identity in identity()) {
+        let recipient = Recipient::try_from(identity).unwrap();
+        let bytes = recipient.to_bytes();
+        let encoded = recipient_to_string(PLUGIN_NAME, &bytes).unwrap();
+        prop_assert_eq!(recipient_from_string(&encoded).unwrap(), bytes);
+    }
+
+    #[test]
+    fn bech32_roundtrip(
+        hrp in "[a-z][a-z0-9-]{0,15}",
+        data in prop::collection::vec(any::<u8>(), 0..2048),
+    ) {
+        let encoded = encoding::encode(&hrp, &data).unwrap();
+        prop_assert_eq!(encoded.len(), encoding::encoded_len(&hrp, data.len()));
+        prop_assert_eq!(encoding::decode(&encoded).unwrap(), (hrp.clone(), data.clone()));
+        prop_assert_eq!(encoding::decode(&encoded.to_uppercase()).unwrap(), (hrp, data));
+    }
+
+    #[test]
+    fn parse_arbitrary_strings(s in "\\PC*") {
+        let _ = encoding::decode(&s);
+        let _ = identity_from_string(&s);
+        let _ = recipient_from_string(&s);
+    }
+
+    #[test]
+    fn parse_arbitrary_bytes(data in prop::collection::vec(any::<u8>(), 0..4096)) {
+        let _ = Identity::from_bytes(&data);
+        let _ = Recipient::from_bytes(&data);
+    }
+
+    #[test]
+    fn parse_mutated_keys(identity in identity(), index in any::<prop::sample::Index>(), byte in any::<u8>()) {
+        let recipient = Recipient::try_from(identity.clone()).unwrap();
+        for mut bytes in [identity.to_bytes().to_vec(), recipient.to_bytes()] {
+            let index = index.index(bytes.len());
+            bytes[index] = byte;
+            let _ = Identity::from_bytes(&bytes);
+            let _ = Recipient::from_bytes(&bytes);
+            let _ = Identity::from_bytes(&bytes[..index]);
+            let _ = Recipient::from_bytes(&bytes[..index]);
+        }
+    }
+
+    #[test]
+    fn unwrap_arbitrary_stanzas(
+        identity in identity(),
+        tag in prop_oneof![Just("hpke".to_owned()), Just("hpke-export".to_owned()), "\\PC*"],
+        args in prop::collection::vec("[A-Za-z0-9+/]{0,64}", 0..4),
+        body in prop::collection::vec(any::<u8>(), 0..64),
+    ) {
+        let stanza = Stanza { tag, args, body };
+        let _ = identity.unwrap_stanza(&stanza);
+    }
+}
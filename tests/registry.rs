@@ -0,0 +1,279 @@
+//! Suites and KEMs registered at runtime, in their own process as the registry is global.
+
+use std::sync::Arc;
+
+use age_core::{format::FileKey, secrecy::ExposeSecret};
+use age_plugin_hpke::{
+    agile::{
+        agile_gen_keypair, register_kem, register_suite, supported_suites, AeadAlg, AgileAeadCtxR,
+        AgileAeadCtxS, AgileEncappedKey, AgileHpkeError, AgileKem, AgileKeypair, AgileOpModeR,
+        AgileOpModeS, AgilePrivateKey, AgilePublicKey, AgileRng, AgileSuite, HpkeSuite, KdfAlg,
+        KemAlg, SuiteId,
+    },
+    Identity, Recipient,
+};
+use hpke_pq::{
+    aead::AesGcm256,
+    kdf::HkdfSha384,
+    kem::{DhP384HkdfSha384, Kem as _},
+    Deserializable, Serializable,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+// Ids of KEMs that are never built in, implemented with the hpke_pq P-384 KEM
+const CUSTOM_KEM_ID: u16 = 0x7F11;
+// Declares less ephemeral ikm than the P-384 KEM reads
+const SHORT_IKM_KEM_ID: u16 = 0x7F12;
+
+struct CustomKem {
+    id: u16,
+    ephemeral_ikm_len: usize,
+}
+
+impl CustomKem {
+    fn new(id: u16) -> Self {
+        Self {
+            id,
+            ephemeral_ikm_len: 48,
+        }
+    }
+
+    fn keypair(
+        &self,
+        sk: <DhP384HkdfSha384 as hpke_pq::kem::Kem>::PrivateKey,
+        pk: <DhP384HkdfSha384 as hpke_pq::kem::Kem>::PublicKey,
+    ) -> AgileKeypair {
+        AgileKeypair::new(
+            AgilePrivateKey::new(KemAlg::Other(self.id), &sk.to_bytes()),
+            AgilePublicKey::new(KemAlg::Other(self.id), &pk.to_bytes()),
+        )
+    }
+}
+
+impl AgileKem for CustomKem {
+    fn id(&self) -> u16 {
+        self.id
+    }
+
+    fn encapped_key_len(&self) -> usize {
+        97
+    }
+
+    fn ephemeral_ikm_len(&self) -> usize {
+        self.ephemeral_ikm_len
+    }
+
+    fn kdf_alg(&self) -> KdfAlg {
+        KdfAlg::HkdfSha384
+    }
+
+    fn gen_keypair(&self, mut csprng: &mut dyn AgileRng) -> Result<AgileKeypair, AgileHpkeError> {
+        let (sk, pk) = DhP384HkdfSha384::gen_keypair(&mut csprng);
+        Ok(self.keypair(sk, pk))
+    }
+
+    fn derive_keypair(&self, ikm: &[u8]) -> Result<AgileKeypair, AgileHpkeError> {
+        let (sk, pk) = DhP384HkdfSha384::derive_keypair(ikm);
+        Ok(self.keypair(sk, pk))
+    }
+
+    fn validate_public_key(&self, pk: &AgilePublicKey) -> Result<(), AgileHpkeError> {
+        <DhP384HkdfSha384 as hpke_pq::kem::Kem>::PublicKey::from_bytes(&pk.to_bytes())?;
+        Ok(())
+    }
+
+    fn validate_private_key(&self, sk: &AgilePrivateKey) -> Result<(), AgileHpkeError> {
+        <DhP384HkdfSha384 as hpke_pq::kem::Kem>::PrivateKey::from_bytes(&sk.to_bytes())?;
+        Ok(())
+    }
+
+    fn sk_to_pk(&self, sk: &AgilePrivateKey) -> Result<AgilePublicKey, AgileHpkeError> {
+        let sk = <DhP384HkdfSha384 as hpke_pq::kem::Kem>::PrivateKey::from_bytes(&sk.to_bytes())?;
+        Ok(AgilePublicKey::new(
+            KemAlg::Other(self.id),
+            &DhP384HkdfSha384::sk_to_pk(&sk).to_bytes(),
+        ))
+    }
+}
+
+// The P-384 suite, under the id of a custom KEM
+struct CustomSuite(u16, HpkeSuite<AesGcm256, HkdfSha384, DhP384HkdfSha384>);
+
+impl AgileSuite for CustomSuite {
+    fn id(&self) -> SuiteId {
+        SuiteId {
+            kem: self.0,
+            ..self.1.id()
+        }
+    }
+
+    fn setup_sender(
+        &self,
+        mode: &AgileOpModeS,
+        pk_recip: &AgilePublicKey,
+        info: &[u8],
+        csprng: &mut dyn AgileRng,
+    ) -> Result<(AgileEncappedKey, Box<dyn AgileAeadCtxS>), AgileHpkeError> {
+        self.1.setup_sender(mode, pk_recip, info, csprng)
+    }
+
+    fn setup_receiver(
+        &self,
+        mode: &AgileOpModeR,
+        recip_keypair: &AgileKeypair,
+        encapped_key: &AgileEncappedKey,
+        info: &[u8],
+    ) -> Result<Box<dyn AgileAeadCtxR>, AgileHpkeError> {
+        self.1
+            .setup_receiver(mode, recip_keypair, encapped_key, info)
+    }
+}
+
+fn wrap_unwrap(identity: &dyn age::Identity, recipient: &dyn age::Recipient) {
+    let file_key = FileKey::from([7; 16]);
+    let stanzas = recipient.wrap_file_key(&file_key).unwrap();
+    let unwrapped = identity.unwrap_stanza(&stanzas[0]).unwrap().unwrap();
+    assert_eq!(unwrapped.expose_secret(), file_key.expose_secret());
+}
+
+#[test]
+fn register_additional_kem() {
+    let (kem, kdf, aead) = (
+        KemAlg::Other(CUSTOM_KEM_ID),
+        KdfAlg::HkdfSha384,
+        AeadAlg::AesGcm256,
+    );
+    assert!(KemAlg::try_from_u16(CUSTOM_KEM_ID).is_err());
+    assert!(agile_gen_keypair(kem.clone(), &mut StdRng::from_entropy()).is_err());
+
+    register_kem(Arc::new(CustomKem::new(CUSTOM_KEM_ID))).unwrap();
+    assert!(matches!(
+        register_kem(Arc::new(CustomKem::new(CUSTOM_KEM_ID))),
+        Err(AgileHpkeError::KemAlreadyRegistered(CUSTOM_KEM_ID))
+    ));
+    register_suite(Arc::new(CustomSuite(CUSTOM_KEM_ID, HpkeSuite::new()))).unwrap();
+    assert!(matches!(
+        register_suite(Arc::new(CustomSuite(CUSTOM_KEM_ID, HpkeSuite::new()))),
+        Err(AgileHpkeError::SuiteAlreadyRegistered(_))
+    ));
+    assert_eq!(KemAlg::try_from_u16(CUSTOM_KEM_ID).unwrap(), kem);
+    assert!(supported_suites().contains(&(aead.clone(), kdf.clone(), kem.clone())));
+
+    // Identities of the KEM load, derive their recipient, and unwrap their stanzas
+    let keypair = agile_gen_keypair(kem.clone(), &mut StdRng::from_entropy()).unwrap();
+    let identity = Identity::new(kem, aead, kdf, keypair.private_key(), b"ad");
+    let identity = Identity::from_bytes(&identity.to_bytes()).unwrap();
+    let recipient = Recipient::try_from(identity.clone()).unwrap();
+    assert_eq!(recipient.public_key(), keypair.public_key());
+    let recipient = Recipient::from_bytes(&recipient.to_bytes()).unwrap();
+    wrap_unwrap(&identity, &recipient);
+}
+
+#[test]
+fn ephemeral_ikm_overrun_is_an_error() {
+    use age_plugin_hpke::agile::{
+        agile_setup_sender_with_ephemeral_ikm, AgileOpModeS, AgileOpModeSTy,
+    };
+
+    let kem = KemAlg::Other(SHORT_IKM_KEM_ID);
+    register_kem(Arc::new(CustomKem {
+        id: SHORT_IKM_KEM_ID,
+        ephemeral_ikm_len: 16,
+    }))
+    .unwrap();
+    register_suite(Arc::new(CustomSuite(SHORT_IKM_KEM_ID, HpkeSuite::new()))).unwrap();
+
+    // The P-384 KEM reads 48 bytes of the 16 declared
+    let keypair = agile_gen_keypair(kem.clone(), &mut StdRng::from_entropy()).unwrap();
+    let res = agile_setup_sender_with_ephemeral_ikm(
+        AeadAlg::AesGcm256,
+        KdfAlg::HkdfSha384,
+        kem.clone(),
+        &AgileOpModeS::new(kem, AgileOpModeSTy::Base),
+        keypair.public_key(),
+        b"",
+        &[1u8; 16],
+    );
+    assert!(matches!(
+        res,
+        Err(AgileHpkeError::HpkeError(
+            hpke_pq::HpkeError::IncorrectInputLength(48, 16)
+        ))
+    ));
+}
+
+#[cfg(all(feature = "x25519", feature = "chacha20poly1305"))]
+#[test]
+fn replace_builtin_suite() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use age_plugin_hpke::agile::replace_suite;
+
+    // Delegates to another suite, counting the calls
+    struct CountingSuite {
+        inner: Arc<dyn AgileSuite>,
+        calls: AtomicUsize,
+    }
+
+    impl AgileSuite for CountingSuite {
+        fn id(&self) -> SuiteId {
+            self.inner.id()
+        }
+
+        fn setup_sender(
+            &self,
+            mode: &AgileOpModeS,
+            pk_recip: &AgilePublicKey,
+            info: &[u8],
+            csprng: &mut dyn AgileRng,
+        ) -> Result<(AgileEncappedKey, Box<dyn AgileAeadCtxS>), AgileHpkeError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner.setup_sender(mode, pk_recip, info, csprng)
+        }
+
+        fn setup_receiver(
+            &self,
+            mode: &AgileOpModeR,
+            recip_keypair: &AgileKeypair,
+            encapped_key: &AgileEncappedKey,
+            info: &[u8],
+        ) -> Result<Box<dyn AgileAeadCtxR>, AgileHpkeError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.inner
+                .setup_receiver(mode, recip_keypair, encapped_key, info)
+        }
+    }
+
+    let (kem, kdf, aead) = (
+        KemAlg::X25519HkdfSha256,
+        KdfAlg::HkdfSha256,
+        AeadAlg::ChaCha20Poly1305,
+    );
+
+    let id = SuiteId::new(&aead, &kdf, &kem);
+    let inner = Arc::new(HpkeSuite::<
+        hpke_pq::aead::ChaCha20Poly1305,
+        hpke_pq::kdf::HkdfSha256,
+        hpke_pq::kem::X25519HkdfSha256,
+    >::new());
+    assert_eq!(inner.id(), id);
+    let counting = Arc::new(CountingSuite {
+        inner,
+        calls: AtomicUsize::new(0),
+    });
+
+    // Built in suites are only replaced explicitly
+    assert!(matches!(
+        register_suite(counting.clone()),
+        Err(AgileHpkeError::SuiteAlreadyRegistered(_))
+    ));
+    let previous = replace_suite(counting.clone()).unwrap();
+    assert_eq!(previous.id(), id);
+
+    let keypair = agile_gen_keypair(kem.clone(), &mut StdRng::from_entropy()).unwrap();
+    let identity = Identity::new(kem, aead, kdf, keypair.private_key(), b"ad");
+    wrap_unwrap(&identity, &Recipient::try_from(identity.clone()).unwrap());
+    assert_eq!(counting.calls.load(Ordering::SeqCst), 2);
+
+    replace_suite(previous);
+}
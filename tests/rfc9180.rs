@@ -0,0 +1,500 @@
+//! Known-answer tests of the agile HPKE layer.
+//!
+//! `tests/data/test-vectors-5f503c5.json` are the RFC 9180 test vectors from the CFRG repository,
+//! and `tests/data/test-vectors-xyber768d00-02.json` the X25519Kyber768Draft00 ones, both as
+//! vendored by hpke_pq. Vectors of suites the agile layer does not support are skipped.
+
+use std::{collections::HashSet, fs};
+
+use age_plugin_hpke::agile::{
+    agile_derive_keypair, agile_open, agile_seal, agile_setup_receiver, agile_setup_sender,
+    agile_setup_sender_with_ephemeral_ikm, is_supported_suite, supported_suites, AeadAlg,
+    AgileEncappedKey, AgileHpkeError, AgileOpModeR, AgileOpModeRTy, AgileOpModeS, AgileOpModeSTy,
+    AgilePskBundle, AgilePublicKey, AgileSequenceR, AgileSequenceS, KdfAlg, KemAlg,
+};
+use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
+use serde::{Deserialize, Deserializer};
+
+const VECTOR_FILES: [&str; 2] = [
+    "tests/data/test-vectors-5f503c5.json",
+    "tests/data/test-vectors-xyber768d00-02.json",
+];
+
+fn from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    hex::decode(s).map_err(serde::de::Error::custom)
+}
+
+fn from_hex_opt<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+    from_hex(deserializer).map(Some)
+}
+
+#[derive(Deserialize)]
+struct TestVector {
+    mode: u8,
+    kem_id: u16,
+    kdf_id: u16,
+    aead_id: u16,
+    #[serde(deserialize_with = "from_hex")]
+    info: Vec<u8>,
+    #[serde(rename = "ikmR", deserialize_with = "from_hex")]
+    ikm_r: Vec<u8>,
+    #[serde(rename = "ikmS", default, deserialize_with = "from_hex_opt")]
+    ikm_s: Option<Vec<u8>>,
+    #[serde(rename = "ikmE", default, deserialize_with = "from_hex_opt")]
+    ikm_e: Option<Vec<u8>>,
+    // Encapsulation randomness of X25519Kyber768Draft00
+    #[serde(default, deserialize_with = "from_hex_opt")]
+    ier: Option<Vec<u8>>,
+    #[serde(rename = "pkRm", deserialize_with = "from_hex")]
+    pk_rm: Vec<u8>,
+    #[serde(rename = "pkSm", default, deserialize_with = "from_hex_opt")]
+    pk_sm: Option<Vec<u8>>,
+    #[serde(default, deserialize_with = "from_hex_opt")]
+    psk: Option<Vec<u8>>,
+    #[serde(default, deserialize_with = "from_hex_opt")]
+    psk_id: Option<Vec<u8>>,
+    #[serde(deserialize_with = "from_hex")]
+    enc: Vec<u8>,
+    encryptions: Vec<Encryption>,
+    exports: Vec<Export>,
+}
+
+#[derive(Deserialize)]
+struct Encryption {
+    #[serde(deserialize_with = "from_hex")]
+    aad: Vec<u8>,
+    #[serde(deserialize_with = "from_hex")]
+    ct: Vec<u8>,
+    #[serde(deserialize_with = "from_hex")]
+    pt: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct Export {
+    #[serde(deserialize_with = "from_hex")]
+    exporter_context: Vec<u8>,
+    #[serde(rename = "L")]
+    len: usize,
+    #[serde(deserialize_with = "from_hex")]
+    exported_value: Vec<u8>,
+}
+
+fn load_vectors() -> Vec<TestVector> {
+    VECTOR_FILES
+        .iter()
+        .flat_map(|path| {
+            let content = fs::read_to_string(path).unwrap();
+            serde_json::from_str::<Vec<TestVector>>(&content).unwrap()
+        })
+        .collect()
+}
+
+// Replays the ephemeral ikm of a vector as randomness, so that single-shot sealing is
+// deterministic
+struct ReplayRng<'a>(&'a [u8]);
+
+impl RngCore for ReplayRng<'_> {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0u8; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let (head, tail) = self.0.split_at(dest.len());
+        dest.copy_from_slice(head);
+        self.0 = tail;
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for ReplayRng<'_> {}
+
+// Runs a test vector, returns false if its suite is not supported
+fn run_vector(tv: &TestVector) -> bool {
+    let (Ok(kem), Ok(kdf), Ok(aead)) = (
+        KemAlg::try_from_u16(tv.kem_id),
+        KdfAlg::try_from_u16(tv.kdf_id),
+        AeadAlg::try_from_u16(tv.aead_id),
+    ) else {
+        return false;
+    };
+    if !is_supported_suite(&aead, &kdf, &kem) {
+        return false;
+    }
+
+    // X25519 private keys are stored clamped, and differ from skRm. Receiving checks them.
+    let recip_keypair = agile_derive_keypair(kem.clone(), &tv.ikm_r).unwrap();
+    assert_eq!(recip_keypair.public_key().to_bytes(), tv.pk_rm);
+
+    let sender_keypair = tv
+        .ikm_s
+        .as_ref()
+        .map(|ikm_s| agile_derive_keypair(kem.clone(), ikm_s).unwrap());
+    if let Some(keypair) = &sender_keypair {
+        assert_eq!(Some(keypair.public_key().to_bytes()), tv.pk_sm);
+    }
+    let psk_bundle = tv
+        .psk
+        .as_ref()
+        .map(|psk| AgilePskBundle::new(psk, tv.psk_id.as_ref().unwrap()));
+
+    let (mode_s, mode_r) = match tv.mode {
+        0 => (AgileOpModeSTy::Base, AgileOpModeRTy::Base),
+        1 => (
+            AgileOpModeSTy::Psk(psk_bundle.clone().unwrap()),
+            AgileOpModeRTy::Psk(psk_bundle.unwrap()),
+        ),
+        2 => {
+            let keypair = sender_keypair.unwrap();
+            let pk = keypair.public_key().clone();
+            (AgileOpModeSTy::Auth(keypair), AgileOpModeRTy::Auth(pk))
+        }
+        3 => {
+            let keypair = sender_keypair.unwrap();
+            let pk = keypair.public_key().clone();
+            (
+                AgileOpModeSTy::AuthPsk(keypair, psk_bundle.clone().unwrap()),
+                AgileOpModeRTy::AuthPsk(pk, psk_bundle.unwrap()),
+            )
+        }
+        mode => panic!("invalid mode {mode}"),
+    };
+
+    let ikm_e = tv.ikm_e.as_ref().or(tv.ier.as_ref()).unwrap();
+    let mode_s = AgileOpModeS::new(kem.clone(), mode_s);
+    let (encapped_key, sender_ctx) = agile_setup_sender_with_ephemeral_ikm(
+        aead.clone(),
+        kdf.clone(),
+        kem.clone(),
+        &mode_s,
+        recip_keypair.public_key(),
+        &tv.info,
+        ikm_e,
+    )
+    .unwrap();
+    assert_eq!(encapped_key.to_bytes(), tv.enc);
+    assert_eq!(encapped_key.kem_alg(), &kem);
+    assert_eq!(encapped_key.expected_len(), tv.enc.len());
+
+    let mode_r = AgileOpModeR::new(kem.clone(), mode_r);
+    let receiver_ctx = agile_setup_receiver(
+        aead.clone(),
+        kdf.clone(),
+        kem.clone(),
+        &mode_r,
+        &recip_keypair,
+        &encapped_key,
+        &tv.info,
+    )
+    .unwrap();
+
+    // Encryptions are in sequence order, each one uses the next nonce
+    let mut sender_ctx = AgileSequenceS::new(sender_ctx);
+    let mut receiver_ctx = AgileSequenceR::new(receiver_ctx);
+    for (seq, encryption) in tv.encryptions.iter().enumerate() {
+        assert_eq!(sender_ctx.seq(), seq as u64);
+        let ct = sender_ctx.seal(&encryption.pt, &encryption.aad).unwrap();
+        assert_eq!(ct, encryption.ct);
+        let pt = receiver_ctx.open(&ct, &encryption.aad).unwrap();
+        assert_eq!(pt, encryption.pt);
+    }
+
+    // Single-shot sealing and opening are the first message of a context
+    if let Some(encryption) = tv.encryptions.first() {
+        let (single_encapped_key, ct) = agile_seal(
+            aead.clone(),
+            kdf.clone(),
+            kem.clone(),
+            &mode_s,
+            recip_keypair.public_key(),
+            &tv.info,
+            &encryption.pt,
+            &encryption.aad,
+            &mut ReplayRng(ikm_e),
+        )
+        .unwrap();
+        assert_eq!(single_encapped_key.to_bytes(), tv.enc);
+        assert_eq!(ct, encryption.ct);
+
+        let pt = agile_open(
+            aead,
+            kdf,
+            kem,
+            &mode_r,
+            &recip_keypair,
+            &AgileEncappedKey::new(encapped_key.kem_alg().clone(), &tv.enc),
+            &tv.info,
+            &encryption.ct,
+            &encryption.aad,
+        )
+        .unwrap();
+        assert_eq!(pt, encryption.pt);
+    }
+
+    for export in &tv.exports {
+        let sender_value = sender_ctx
+            .export(&export.exporter_context, export.len)
+            .unwrap();
+        let receiver_value = receiver_ctx
+            .export(&export.exporter_context, export.len)
+            .unwrap();
+        assert_eq!(sender_value, export.exported_value);
+        assert_eq!(receiver_value, export.exported_value);
+    }
+
+    true
+}
+
+#[test]
+fn rfc9180_test_vectors() {
+    let mut suites = HashSet::new();
+    for tv in load_vectors() {
+        if run_vector(&tv) {
+            suites.insert((tv.mode, tv.kem_id, tv.kdf_id, tv.aead_id));
+        }
+    }
+
+    // RFC 9180 KEMs have vectors in every mode and for every AEAD. X25519Kyber768Draft00 only has
+    // AesGcm128 vectors, and there are no P-384 vectors
+    for (aead, _, kem) in supported_suites() {
+        if matches!(kem, KemAlg::X25519HkdfSha256 | KemAlg::DhP256HkdfSha256) {
+            let kem_id = kem.to_u16();
+            for mode in 0..4 {
+                assert!(suites
+                    .iter()
+                    .any(|suite| suite.0 == mode && suite.1 == kem_id));
+            }
+            assert!(suites
+                .iter()
+                .any(|suite| suite.1 == kem_id && suite.3 == aead.to_u16()));
+        }
+    }
+    if cfg!(all(feature = "pq-hybrid", feature = "aes-gcm")) {
+        let kem_id = KemAlg::X25519Kyber768Draft00.to_u16();
+        assert!(suites.iter().any(|suite| suite.1 == kem_id));
+    }
+}
+
+#[cfg(all(feature = "x25519", feature = "aes-gcm"))]
+#[test]
+fn ephemeral_ikm_length_is_checked() {
+    let kem = KemAlg::X25519HkdfSha256;
+    let keypair = agile_derive_keypair(kem.clone(), &[1u8; 32]).unwrap();
+    let res = agile_setup_sender_with_ephemeral_ikm(
+        AeadAlg::AesGcm128,
+        KdfAlg::HkdfSha256,
+        kem.clone(),
+        &AgileOpModeS::new(kem, AgileOpModeSTy::Base),
+        keypair.public_key(),
+        b"",
+        &[2u8; 31],
+    );
+    assert!(res.is_err());
+}
+
+// The modes own a copy of the preshared key, the buffer it is read from can be dropped
+#[cfg(all(feature = "x25519", feature = "chacha20poly1305"))]
+fn psk_modes(kem: &KemAlg, psk: &str) -> (AgileOpModeS, AgileOpModeR) {
+    let psk = psk.as_bytes().to_vec();
+    let psk_id = b"psk id".to_vec();
+    let bundle = AgilePskBundle::new(&psk, &psk_id);
+    (
+        AgileOpModeS::new(kem.clone(), AgileOpModeSTy::Psk(bundle.clone())),
+        AgileOpModeR::new(kem.clone(), AgileOpModeRTy::Psk(bundle)),
+    )
+}
+
+#[cfg(all(feature = "x25519", feature = "chacha20poly1305"))]
+#[test]
+fn psk_modes_own_their_secrets() {
+    let kem = KemAlg::X25519HkdfSha256;
+    let keypair = agile_derive_keypair(kem.clone(), &[1u8; 32]).unwrap();
+    let (mode_s, _) = psk_modes(&kem, "preshared key loaded at runtime");
+    let (encapped_key, mut sender_ctx) = agile_setup_sender(
+        AeadAlg::ChaCha20Poly1305,
+        KdfAlg::HkdfSha256,
+        kem.clone(),
+        &mode_s,
+        keypair.public_key(),
+        b"",
+        &mut StdRng::seed_from_u64(0),
+    )
+    .unwrap();
+    let ct = sender_ctx.seal(b"plaintext", b"").unwrap();
+
+    for (psk, opens) in [
+        ("preshared key loaded at runtime", true),
+        ("another preshared key", false),
+    ] {
+        let (_, mode_r) = psk_modes(&kem, psk);
+        let mut receiver_ctx = agile_setup_receiver(
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            kem.clone(),
+            &mode_r,
+            &keypair,
+            &encapped_key,
+            b"",
+        )
+        .unwrap();
+        assert_eq!(receiver_ctx.open(&ct, b"").is_ok(), opens);
+    }
+}
+
+#[cfg(all(feature = "x25519", feature = "chacha20poly1305"))]
+#[test]
+fn single_shot_round_trip() {
+    use hpke_pq::HpkeError;
+
+    let kem = KemAlg::X25519HkdfSha256;
+    let keypair = agile_derive_keypair(kem.clone(), &[1u8; 32]).unwrap();
+    let (encapped_key, ct) = agile_seal(
+        AeadAlg::ChaCha20Poly1305,
+        KdfAlg::HkdfSha256,
+        kem.clone(),
+        &AgileOpModeS::new(kem.clone(), AgileOpModeSTy::Base),
+        keypair.public_key(),
+        b"info",
+        b"plaintext",
+        b"aad",
+        &mut StdRng::seed_from_u64(0),
+    )
+    .unwrap();
+
+    let open = |encapped_key: &AgileEncappedKey| {
+        agile_open(
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            kem.clone(),
+            &AgileOpModeR::new(kem.clone(), AgileOpModeRTy::Base),
+            &keypair,
+            encapped_key,
+            b"info",
+            &ct,
+            b"aad",
+        )
+    };
+    assert_eq!(open(&encapped_key).unwrap(), b"plaintext");
+
+    let truncated = AgileEncappedKey::new(kem.clone(), &encapped_key.to_bytes()[1..]);
+    assert!(matches!(
+        open(&truncated),
+        Err(AgileHpkeError::HpkeError(HpkeError::IncorrectInputLength(
+            32, 31
+        )))
+    ));
+}
+
+#[cfg(all(feature = "x25519", feature = "chacha20poly1305"))]
+#[test]
+fn message_limit_is_an_error() {
+    use hpke_pq::HpkeError;
+
+    let kem = KemAlg::X25519HkdfSha256;
+    let keypair = agile_derive_keypair(kem.clone(), &[1u8; 32]).unwrap();
+    let (encapped_key, sender_ctx) = agile_setup_sender(
+        AeadAlg::ChaCha20Poly1305,
+        KdfAlg::HkdfSha256,
+        kem.clone(),
+        &AgileOpModeS::new(kem.clone(), AgileOpModeSTy::Base),
+        keypair.public_key(),
+        b"",
+        &mut StdRng::seed_from_u64(0),
+    )
+    .unwrap();
+    let receiver_ctx = agile_setup_receiver(
+        AeadAlg::ChaCha20Poly1305,
+        KdfAlg::HkdfSha256,
+        kem.clone(),
+        &AgileOpModeR::new(kem, AgileOpModeRTy::Base),
+        &keypair,
+        &encapped_key,
+        b"",
+    )
+    .unwrap();
+
+    let mut sender_ctx = AgileSequenceS::new(sender_ctx).with_message_limit(2);
+    let messages: [(&[u8], &[u8]); 2] = [(b"first", b""), (b"second", b"")];
+    let cts = sender_ctx.seal_all(&messages).unwrap();
+    assert_eq!(sender_ctx.seq(), 2);
+    assert!(matches!(
+        sender_ctx.seal(b"third", b""),
+        Err(AgileHpkeError::HpkeError(HpkeError::MessageLimitReached))
+    ));
+
+    let mut receiver_ctx = AgileSequenceR::new(receiver_ctx).with_message_limit(2);
+    // Opening out of order fails, and does not advance the sequence
+    assert!(receiver_ctx.open(&cts[1], b"").is_err());
+    assert_eq!(receiver_ctx.seq(), 0);
+    let cts: Vec<(&[u8], &[u8])> = cts.iter().map(|ct| (ct.as_slice(), &b""[..])).collect();
+    let pts = receiver_ctx.open_all(&cts).unwrap();
+    assert_eq!(pts, [b"first".to_vec(), b"second".to_vec()]);
+    assert!(matches!(
+        receiver_ctx.open(cts[0].0, b""),
+        Err(AgileHpkeError::HpkeError(HpkeError::MessageLimitReached))
+    ));
+}
+
+#[test]
+fn unsupported_suite_is_an_error() {
+    // No X448 suite is dispatched
+    let kem = KemAlg::X448HkdfSha512;
+    let public_key = AgilePublicKey::new(kem.clone(), &[1u8; 56]);
+    let res = agile_setup_sender(
+        AeadAlg::AesGcm256,
+        KdfAlg::HkdfSha512,
+        kem.clone(),
+        &AgileOpModeS::new(kem.clone(), AgileOpModeSTy::Base),
+        &public_key,
+        b"",
+        &mut StdRng::from_entropy(),
+    );
+    assert!(matches!(
+        res,
+        Err(AgileHpkeError::UnsupportedSuite {
+            aead: AeadAlg::AesGcm256,
+            kdf: KdfAlg::HkdfSha512,
+            kem: KemAlg::X448HkdfSha512,
+        })
+    ));
+    assert!(!is_supported_suite(
+        &AeadAlg::AesGcm256,
+        &KdfAlg::HkdfSha512,
+        &kem
+    ));
+}
+
+#[test]
+fn supported_suites_are_supported() {
+    // Every KDF is available to every enabled KEM and AEAD
+    let kems = [
+        cfg!(feature = "x25519"),
+        cfg!(feature = "pq-hybrid"),
+        cfg!(feature = "nist-p256"),
+        cfg!(feature = "nist-p384"),
+    ];
+    let aeads = [
+        cfg!(feature = "aes-gcm"),
+        cfg!(feature = "aes-gcm"),
+        cfg!(feature = "chacha20poly1305"),
+        cfg!(feature = "export-only"),
+    ];
+    let enabled = |features: &[bool]| features.iter().filter(|enabled| **enabled).count();
+    let suites = supported_suites();
+    assert_eq!(suites.len(), enabled(&kems) * 3 * enabled(&aeads));
+    for (aead, kdf, kem) in suites {
+        assert!(is_supported_suite(&aead, &kdf, &kem));
+    }
+}